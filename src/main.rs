@@ -1,12 +1,26 @@
 mod meta;
 use log::*;
+use std::env;
+use std::io;
+use std::path::Path;
 use std::process;
 use std::env::consts;
+use meta::baseline;
+use meta::config::Config;
 use meta::watcher::Watcher;
 
 
 fn main() -> ! {
     pretty_env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("baseline") {
+        let path = Config::from_env().baseline.unwrap_or_else(|| String::from(baseline::DEFAULT_PATH));
+        if let Err(e) = baseline::command(Path::new(&path), &args[1..], &mut io::stdout()) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
     unsafe {
         if libc::geteuid() != 0 {
             error!("Application must run as root");
@@ -18,7 +32,8 @@ fn main() -> ! {
         process::exit(2);
     }
     info!("monitoring started processes...");
-    let mut watcher = Watcher::new().unwrap();
+    let config = Config::from_env();
+    let mut watcher = Watcher::new(config).unwrap();
     
     watcher.main_loop()
 }
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use metrics::{GaugeValue, Key, Recorder, Unit};
+
+/// Upper bounds of histogram buckets in seconds
+pub const BOUNDS: [f64; 12] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0, 3600.0];
+/// Upper bounds of histogram buckets in bytes, from 1MiB to 16GiB
+pub const BYTES: [f64; 8] = [1048576., 4194304., 16777216., 67108864., 268435456., 1073741824.,
+    4294967296., 17179869184.];
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub bounds: &'static [f64],
+    /// Number of values in every bucket, the last one is for values above the bounds
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    /// Picks bounds by the unit suffix of the metric name
+    fn new(name: &str) -> Self {
+        let bounds: &'static [f64] = match name.ends_with("_bytes") {
+            true => &BYTES,
+            false => &BOUNDS,
+        };
+        Self{bounds, counts: vec![0; bounds.len() + 1], sum: 0., count: 0}
+    }
+
+    fn record(&mut self, value: f64) {
+        let bucket = self.bounds.iter().position(|b| value <= *b).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Store {
+    pub counters: HashMap<Key, u64>,
+    pub gauges: HashMap<Key, f64>,
+    pub histograms: HashMap<Key, Histogram>,
+}
+
+/// Keeps current values of all metrics, so that they could be periodically
+/// pushed elsewhere
+#[derive(Clone)]
+pub struct Aggregator {
+    store: Arc<Mutex<Store>>,
+    /// When the counters and histograms started
+    pub start: SystemTime,
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Self{store: Arc::new(Mutex::new(Store::default())), start: SystemTime::now()}
+    }
+
+    /// Returns current values of all metrics
+    pub fn store(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap()
+    }
+}
+
+impl Recorder for Aggregator {
+    fn register_counter(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_gauge(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn increment_counter(&self, key: &Key, value: u64) {
+        *self.store.lock().unwrap().counters.entry(key.clone()).or_default() += value;
+    }
+
+    fn update_gauge(&self, key: &Key, value: GaugeValue) {
+        let mut store = self.store.lock().unwrap();
+        let gauge = store.gauges.entry(key.clone()).or_default();
+        *gauge = value.update_value(*gauge);
+    }
+
+    fn record_histogram(&self, key: &Key, value: f64) {
+        self.store.lock().unwrap().histograms.entry(key.clone())
+            .or_insert_with(|| Histogram::new(key.name()))
+            .record(value);
+    }
+}
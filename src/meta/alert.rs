@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::*;
+use metrics::increment_counter;
+use serde::Serialize;
+use super::event::Event;
+use super::http;
+use super::info::Process;
+
+/// Maximum number of alerts waiting to be sent
+const QUEUE_SIZE: usize = 256;
+/// Number of remembered trees, after which expired ones are forgotten
+const MAX_TREES: usize = 10000;
+
+/// Parent of the process, that matched the rule
+#[derive(Debug, Clone, Serialize)]
+pub struct Ancestor {
+    pub pid: i32,
+    pub exe: String,
+    pub argv: Vec<String>,
+    pub user: String,
+    pub label: String,
+}
+
+impl From<&Process> for Ancestor {
+    fn from(prc: &Process) -> Self {
+        Self{
+            pid: prc.pid,
+            exe: String::from(prc.exe()),
+            argv: prc.argv.clone(),
+            user: prc.user(),
+            label: prc.label.clone(),
+        }
+    }
+}
+
+/// Notification about the rule match
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub rule: &'static str,
+    pub description: &'static str,
+    pub event: Event,
+    /// Parents of the process, closest first
+    pub ancestry: Vec<Ancestor>,
+}
+
+/// Sends alerts as JSON POST requests in the background thread.
+/// Same rule is reported once per tree within the dedup window
+/// and no more than the configured number of alerts is sent per minute.
+pub struct Webhook {
+    queue: SyncSender<Alert>,
+    per_minute: usize,
+    dedup: Duration,
+    reported: HashMap<(&'static str, String), Instant>,
+    recent: VecDeque<Instant>,
+}
+
+impl Webhook {
+    pub fn new(url: String, per_minute: usize, dedup: Duration) -> Result<Self> {
+        let (queue, alerts) = sync_channel::<Alert>(QUEUE_SIZE);
+        thread::Builder::new()
+            .name(String::from("prom-cnproc-webhook"))
+            .spawn(move || {
+                for alert in alerts {
+                    if let Err(e) = send(&url, &alert) {
+                        warn!("cannot send {} alert: {}", alert.rule, e);
+                        increment_counter!("process_webhook_errors_total");
+                    }
+                }
+            })?;
+        Ok(Self{queue, per_minute, dedup, reported: HashMap::new(), recent: VecDeque::new()})
+    }
+
+    /// Schedules the alert, unless it's a duplicate or over the rate limit
+    pub fn notify(&mut self, alert: Alert) {
+        let now = Instant::now();
+        if let Some(reason) = self.suppress(&alert, now) {
+            debug!("suppressed {} alert for {}: {}", alert.rule, alert.event.tree, reason);
+            increment_counter!("process_alerts_suppressed_total", "reason" => reason);
+            return;
+        }
+        if let Err(TrySendError::Full(alert)) = self.queue.try_send(alert) {
+            warn!("webhook queue is full, dropping {} alert", alert.rule);
+            increment_counter!("process_alerts_suppressed_total", "reason" => "queue");
+        }
+    }
+
+    fn suppress(&mut self, alert: &Alert, now: Instant) -> Option<&'static str> {
+        let dedup = self.dedup;
+        let key = (alert.rule, alert.event.tree.clone());
+        if let Some(last) = self.reported.get(&key) {
+            if now.duration_since(*last) < dedup {
+                return Some("dedup");
+            }
+        }
+        while let Some(first) = self.recent.front() {
+            if now.duration_since(*first) < Duration::from_secs(60) {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.per_minute {
+            return Some("rate");
+        }
+        if self.reported.len() >= MAX_TREES {
+            self.reported.retain(|_, last| now.duration_since(*last) < dedup);
+        }
+        self.reported.insert(key, now);
+        self.recent.push_back(now);
+        None
+    }
+}
+
+fn send(url: &str, alert: &Alert) -> Result<u16> {
+    let body = serde_json::to_vec(alert)?;
+    http::request("POST", url, &[("Content-Type", "application/json")], &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::event::Kind;
+    use super::super::http::tests::server;
+
+    fn alert(tree: &str) -> Alert {
+        let sshd = Process::from(2, 1, "/usr/sbin/sshd", vec![String::from("sshd")]);
+        let mut prc = Process::from(3, 2, "/tmp/x", vec![String::from("x")]);
+        prc.tree = String::from(tree);
+        Alert{
+            rule: "unpackaged-under-sshd",
+            description: "",
+            event: Event::new(Kind::Exec, &prc),
+            ancestry: vec![Ancestor::from(&sshd)],
+        }
+    }
+
+    #[test]
+    fn posts_alerts() {
+        let (url, rx) = server(200);
+        let mut webhook = Webhook::new(url, 10, Duration::from_secs(60)).unwrap();
+        webhook.notify(alert("/sshd/x"));
+        let (head, body) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(head.starts_with("POST / HTTP/1.1"));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!("unpackaged-under-sshd", json["rule"]);
+        assert_eq!("/sshd/x", json["event"]["tree"]);
+        assert_eq!("/usr/sbin/sshd", json["ancestry"][0]["exe"]);
+    }
+
+    #[test]
+    fn dedups_and_limits() {
+        let (url, _rx) = server(200);
+        let mut webhook = Webhook::new(url, 2, Duration::from_secs(60)).unwrap();
+        let now = Instant::now();
+        assert_eq!(None, webhook.suppress(&alert("/sshd/x"), now));
+        assert_eq!(Some("dedup"), webhook.suppress(&alert("/sshd/x"), now));
+        assert_eq!(None, webhook.suppress(&alert("/sshd/y"), now));
+        assert_eq!(Some("rate"), webhook.suppress(&alert("/sshd/z"), now));
+        let later = now + Duration::from_secs(61);
+        assert_eq!(None, webhook.suppress(&alert("/sshd/x"), later));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::*;
+use serde::{Deserialize, Serialize};
+use super::event::rfc3339;
+use super::state;
+
+/// Where baseline is kept, unless configured otherwise
+pub const DEFAULT_PATH: &str = "/var/lib/prom-cnproc/baseline.json";
+const VERSION: u32 = 1;
+/// Minimum delay between writes of the baseline file
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Minimum delay between checks for edits from the command line
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tree, that was seen on this host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub count: u64,
+    /// Learned or approved with the command line, otherwise it's unexpected
+    pub approved: bool,
+}
+
+/// Contents of the baseline file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct File {
+    pub version: u32,
+    /// When the learning window has started, seconds since epoch
+    pub learning_since: u64,
+    pub trees: BTreeMap<String, Entry>,
+}
+
+impl File {
+    fn new(now: u64) -> Self {
+        Self{version: VERSION, learning_since: now, trees: BTreeMap::new()}
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file: Self = serde_json::from_slice(&fs::read(path)?)?;
+        if file.version != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} has version {}, expected {}", path.display(), file.version, VERSION)));
+        }
+        Ok(file)
+    }
+
+    /// Loads the file or starts the new baseline, if there's none yet
+    pub fn load_or_new(path: &Path) -> Result<Self> {
+        match Self::load(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::new(now())),
+            other => other,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        state::write(path, &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// What is known about the tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Expected,
+    /// Unexpected tree, that was seen before
+    Unexpected,
+    /// Unexpected tree, that is seen for the first time
+    FirstSeen,
+}
+
+/// Observations since the last save
+#[derive(Debug, Clone)]
+struct Delta {
+    first_seen: u64,
+    last_seen: u64,
+    count: u64,
+    approved: bool,
+}
+
+/// Learns trees during the window and reports unexpected ones afterwards.
+/// Command line may edit the file while exporter is running, so recent
+/// observations are merged into the file on every save.
+pub struct Baseline {
+    path: PathBuf,
+    file: File,
+    learn: Duration,
+    changes: HashMap<String, Delta>,
+    modified: Option<SystemTime>,
+    saved: Instant,
+    checked: Instant,
+}
+
+impl Baseline {
+    pub fn open(path: PathBuf, learn: Duration) -> Result<Self> {
+        let file = File::load_or_new(&path)?;
+        if !path.exists() {
+            // learning window starts now and not on the first save
+            file.save(&path)?;
+        }
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Ok(Self{path, file, learn, changes: HashMap::new(), modified,
+            saved: Instant::now(), checked: Instant::now()})
+    }
+
+    pub fn is_learning(&self, now: u64) -> bool {
+        now < self.file.learning_since.saturating_add(self.learn.as_secs())
+    }
+
+    /// Records the tree and tells if it's expected
+    pub fn observe(&mut self, tree: &str, now: u64) -> Verdict {
+        if self.checked.elapsed() >= RELOAD_INTERVAL {
+            self.checked = Instant::now();
+            if let Err(e) = self.reload() {
+                warn!("cannot reload baseline from {}: {}", self.path.display(), e);
+            }
+        }
+        let learning = self.is_learning(now);
+        let (verdict, approved) = match self.file.trees.get_mut(tree) {
+            Some(entry) => {
+                entry.last_seen = now;
+                entry.count += 1;
+                if learning {
+                    entry.approved = true;
+                }
+                match entry.approved {
+                    true => (Verdict::Expected, true),
+                    false => (Verdict::Unexpected, false),
+                }
+            }
+            None => {
+                let entry = Entry{first_seen: now, last_seen: now, count: 1, approved: learning};
+                self.file.trees.insert(String::from(tree), entry);
+                match learning {
+                    true => (Verdict::Expected, true),
+                    false => (Verdict::FirstSeen, false),
+                }
+            }
+        };
+        let delta = self.changes.entry(String::from(tree))
+            .or_insert(Delta{first_seen: now, last_seen: now, count: 0, approved});
+        delta.last_seen = now;
+        delta.count += 1;
+        delta.approved |= approved;
+        if self.saved.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.save() {
+                warn!("cannot save baseline to {}: {}", self.path.display(), e);
+            }
+        }
+        verdict
+    }
+
+    /// Reloads the file, if it was edited from the command line,
+    /// and merges recent observations into it
+    fn reload(&mut self) -> Result<()> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.modified {
+            return Ok(());
+        }
+        info!("reloading baseline from {}", self.path.display());
+        self.file = File::load_or_new(&self.path)?;
+        self.modified = modified;
+        for (tree, delta) in &self.changes {
+            let entry = self.file.trees.entry(tree.clone()).or_insert(Entry{
+                first_seen: delta.first_seen,
+                last_seen: delta.last_seen,
+                count: 0,
+                approved: false,
+            });
+            entry.last_seen = entry.last_seen.max(delta.last_seen);
+            entry.count += delta.count;
+            entry.approved |= delta.approved;
+        }
+        Ok(())
+    }
+
+    /// Writes recent observations to the file
+    pub fn save(&mut self) -> Result<()> {
+        self.saved = Instant::now();
+        self.reload()?;
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        self.file.save(&self.path)?;
+        self.modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        self.changes.clear();
+        Ok(())
+    }
+}
+
+const USAGE: &str = "usage: prom-cnproc baseline list [--pending]
+       prom-cnproc baseline approve <tree>... | --all
+       prom-cnproc baseline prune <tree>... | --unseen <days>
+       prom-cnproc baseline learn";
+
+fn usage() -> Error {
+    Error::new(ErrorKind::InvalidInput, USAGE)
+}
+
+/// Runs `prom-cnproc baseline` command on the file
+pub fn command(path: &Path, args: &[String], out: &mut impl Write) -> Result<()> {
+    let (cmd, rest) = args.split_first().ok_or_else(usage)?;
+    let mut file = File::load(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+    match (cmd.as_str(), rest.as_slice()) {
+        ("list", flags) => {
+            let pending = match flags {
+                [] => false,
+                ["--pending"] => true,
+                _ => return Err(usage()),
+            };
+            for (tree, entry) in &file.trees {
+                if pending && entry.approved {
+                    continue;
+                }
+                let status = if entry.approved { "approved" } else { "pending" };
+                writeln!(out, "{}\t{}\t{}\t{}\t{}", status, entry.count, time(entry.first_seen),
+                    time(entry.last_seen), tree)?;
+            }
+            return Ok(());
+        }
+        ("approve", ["--all"]) => {
+            for entry in file.trees.values_mut() {
+                entry.approved = true;
+            }
+        }
+        ("approve", trees) if !trees.is_empty() => {
+            for tree in trees {
+                let now = now();
+                let entry = file.trees.entry(String::from(*tree))
+                    .or_insert(Entry{first_seen: now, last_seen: now, count: 0, approved: true});
+                entry.approved = true;
+            }
+        }
+        ("prune", ["--unseen", days]) => {
+            let days: u64 = days.parse().map_err(|_| usage())?;
+            let cutoff = now().saturating_sub(days * 86400);
+            let before = file.trees.len();
+            file.trees.retain(|_, entry| entry.last_seen >= cutoff);
+            writeln!(out, "pruned {} trees", before - file.trees.len())?;
+        }
+        ("prune", trees) if !trees.is_empty() => {
+            for tree in trees {
+                if file.trees.remove(*tree).is_none() {
+                    writeln!(out, "{} is not in the baseline", tree)?;
+                }
+            }
+        }
+        ("learn", []) => file.learning_since = now(),
+        _ => return Err(usage()),
+    }
+    file.save(path)
+}
+
+fn time(secs: u64) -> String {
+    rfc3339(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::TempDir;
+
+    #[test]
+    fn learns_then_enforces() {
+        let dir = TempDir::new("baseline");
+        let path = dir.join("baseline.json");
+        let mut baseline = Baseline::open(path.clone(), Duration::from_secs(100)).unwrap();
+        let start = baseline.file.learning_since;
+        assert_eq!(Verdict::Expected, baseline.observe("/sshd/bash", start + 1));
+        assert_eq!(Verdict::Expected, baseline.observe("/sshd/bash", start + 200));
+        assert_eq!(Verdict::FirstSeen, baseline.observe("/sshd/{random}", start + 200));
+        assert_eq!(Verdict::Unexpected, baseline.observe("/sshd/{random}", start + 201));
+        baseline.save().unwrap();
+
+        let file = File::load(&path).unwrap();
+        assert_eq!(2, file.trees["/sshd/bash"].count);
+        assert!(file.trees["/sshd/bash"].approved);
+        assert!(!file.trees["/sshd/{random}"].approved);
+    }
+
+    #[test]
+    fn keeps_approvals() {
+        let dir = TempDir::new("baseline-approvals");
+        let path = dir.join("baseline.json");
+        let mut baseline = Baseline::open(path.clone(), Duration::from_secs(0)).unwrap();
+        baseline.observe("/cron/backup", now());
+        baseline.save().unwrap();
+
+        // approved from the command line, while exporter keeps running
+        let mut file = File::load(&path).unwrap();
+        file.trees.get_mut("/cron/backup").unwrap().approved = true;
+        file.trees.insert(String::from("/cron/other"), file.trees["/cron/backup"].clone());
+        std::thread::sleep(Duration::from_millis(20));
+        file.save(&path).unwrap();
+        baseline.reload().unwrap();
+
+        assert_eq!(Verdict::Expected, baseline.observe("/cron/backup", now()));
+        baseline.observe("/sshd/top", now());
+        baseline.save().unwrap();
+        let file = File::load(&path).unwrap();
+        assert!(file.trees["/cron/backup"].approved);
+        assert_eq!(2, file.trees["/cron/backup"].count);
+        assert!(file.trees.contains_key("/cron/other"));
+        assert!(file.trees.contains_key("/sshd/top"));
+    }
+
+    #[test]
+    fn commands() {
+        let dir = TempDir::new("baseline-commands");
+        let path = dir.join("baseline.json");
+        let mut baseline = Baseline::open(path.clone(), Duration::from_secs(0)).unwrap();
+        baseline.observe("/cron/backup", now());
+        baseline.observe("/sshd/top", now());
+        baseline.save().unwrap();
+        let run = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let mut out = vec![];
+            command(&path, &args, &mut out).map(|_| String::from_utf8(out).unwrap())
+        };
+        assert!(run(&["approve", "/cron/backup"]).is_ok());
+        let pending = run(&["list", "--pending"]).unwrap();
+        assert!(run(&["prune", "/sshd/top"]).is_ok());
+        let all = run(&["list"]).unwrap();
+        let invalid = run(&["approve"]);
+        assert!(pending.starts_with("pending\t1\t"));
+        assert!(pending.trim_end().ends_with("\t/sshd/top"));
+        assert_eq!(1, all.lines().count());
+        assert!(all.starts_with("approved\t1\t"));
+        assert!(invalid.is_err());
+    }
+}
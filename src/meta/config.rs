@@ -0,0 +1,250 @@
+use std::env;
+use std::str::FromStr;
+use log::*;
+
+/// Runtime configuration, that is read from `CNPROC_*` environment variables.
+/// Systemd unit loads them from `/etc/default/prom-cnproc`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Adds `package` label with the owner of executed file
+    pub package_label: bool,
+    /// Computes SHA-256 of executed binaries and scripts
+    pub hash: bool,
+    /// Name of the detector for random paths: entropy, pattern or ngram
+    pub randomness: String,
+    /// Overrides default threshold of the randomness detector
+    pub randomness_threshold: Option<f32>,
+    /// Labels or binary names, that are not included in the tree
+    pub skip: Vec<String>,
+    /// Maximum number of labels between two same labels, that are rolled up
+    pub collapse: usize,
+    /// Maximum number of labels in the tree
+    pub max_depth: Option<usize>,
+    /// Writes JSON events to `stdout` or to the file
+    pub json: Option<String>,
+    /// Size of the JSON events file, after which it's rotated
+    pub json_max_bytes: u64,
+    /// Number of rotated JSON events files to keep
+    pub json_keep: usize,
+    /// Sends events to `syslog` or `journald`
+    pub syslog: Option<String>,
+    /// Overrides the socket of syslog or journald
+    pub syslog_socket: Option<String>,
+    /// Serves metrics for Prometheus on `127.0.0.1:9501`
+    pub prometheus: bool,
+    /// Base URL of OpenTelemetry collector, that receives OTLP over HTTP
+    pub otlp: Option<String>,
+    /// Seconds between pushes to OpenTelemetry collector
+    pub otlp_interval: u64,
+    /// Sends events to OpenTelemetry collector as log records
+    pub otlp_logs: bool,
+    /// Either `http/json` or `grpc`
+    pub otlp_protocol: String,
+    /// Base URL of Prometheus Pushgateway
+    pub pushgateway: Option<String>,
+    /// URL of Prometheus remote-write endpoint
+    pub remote_write: Option<String>,
+    /// Seconds between pushes to Pushgateway or remote-write endpoint
+    pub push_interval: u64,
+    /// Directory, that keeps failed pushes until they're retried
+    pub push_buffer: Option<String>,
+    /// Maximum number of failed pushes to keep
+    pub push_buffer_max: usize,
+    /// Address of StatsD or DogStatsD agent, like `127.0.0.1:8125`
+    pub statsd: Option<String>,
+    /// Names of enabled rules, all of them by default
+    pub rules: Option<Vec<String>>,
+    /// Names of rules, that are not checked
+    pub rules_disabled: Vec<String>,
+    /// URL, that receives alerts about rule matches
+    pub webhook: Option<String>,
+    /// Maximum number of alerts per minute
+    pub webhook_rate: usize,
+    /// Seconds, during which the same rule is reported only once per tree
+    pub webhook_dedup: u64,
+    /// Unix socket, that streams events to local subscribers
+    pub socket: Option<String>,
+    /// File, that keeps trees, which are expected on this host
+    pub baseline: Option<String>,
+    /// Seconds of learning, after which unknown trees are unexpected
+    pub baseline_learn: u64,
+    /// File, that keeps trees, running processes and counters across restarts
+    pub state: Option<String>,
+    /// Seconds between writes of the state file
+    pub state_interval: u64,
+    /// Seconds between samples of CPU time for miner detection, 0 disables it
+    pub miner_interval: u64,
+    /// Miner suspicion score, after which the process is reported
+    pub miner_threshold: f64,
+    /// Extra regular expression for secrets in command lines of events
+    pub redact: Option<String>,
+    /// Maximum total length of command line arguments in events
+    pub argv_max: usize,
+    /// Environment variables, that are added to exec events
+    pub environ: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self{
+            package_label: false,
+            hash: false,
+            randomness: String::from("entropy"),
+            randomness_threshold: None,
+            // systemd is the mother of all dragons
+            skip: vec![String::from("systemd")],
+            collapse: 0,
+            max_depth: None,
+            json: None,
+            json_max_bytes: 100 * 1024 * 1024,
+            json_keep: 5,
+            syslog: None,
+            syslog_socket: None,
+            prometheus: true,
+            otlp: None,
+            otlp_interval: 10,
+            otlp_logs: false,
+            otlp_protocol: String::from("http/json"),
+            pushgateway: None,
+            remote_write: None,
+            push_interval: 15,
+            push_buffer: None,
+            push_buffer_max: 1000,
+            statsd: None,
+            rules: None,
+            rules_disabled: vec![],
+            webhook: None,
+            webhook_rate: 10,
+            webhook_dedup: 3600,
+            socket: None,
+            baseline: None,
+            baseline_learn: 7 * 86400,
+            state: None,
+            state_interval: 60,
+            miner_interval: 30,
+            miner_threshold: 0.7,
+            redact: None,
+            argv_max: 4096,
+            environ: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::from_vars(env::vars())
+    }
+
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut config = Self::default();
+        for (key, value) in vars {
+            match key.as_str() {
+                "CNPROC_PACKAGE_LABEL" => config.package_label = flag(&key, &value),
+                "CNPROC_HASH" => config.hash = flag(&key, &value),
+                "CNPROC_RANDOMNESS" => config.randomness = value,
+                "CNPROC_RANDOMNESS_THRESHOLD" => config.randomness_threshold = number(&key, &value),
+                "CNPROC_SKIP" => config.skip = list(&value),
+                "CNPROC_COLLAPSE" => config.collapse = number(&key, &value).unwrap_or(config.collapse),
+                "CNPROC_MAX_DEPTH" => config.max_depth = number(&key, &value),
+                "CNPROC_JSON" => config.json = Some(value),
+                "CNPROC_JSON_MAX_BYTES" => config.json_max_bytes = number(&key, &value).unwrap_or(config.json_max_bytes),
+                "CNPROC_JSON_KEEP" => config.json_keep = number(&key, &value).unwrap_or(config.json_keep),
+                "CNPROC_SYSLOG" => config.syslog = Some(value),
+                "CNPROC_SYSLOG_SOCKET" => config.syslog_socket = Some(value),
+                "CNPROC_PROMETHEUS" => config.prometheus = flag(&key, &value),
+                "CNPROC_OTLP" => config.otlp = Some(value),
+                "CNPROC_OTLP_INTERVAL" => config.otlp_interval = number(&key, &value).unwrap_or(config.otlp_interval),
+                "CNPROC_OTLP_LOGS" => config.otlp_logs = flag(&key, &value),
+                "CNPROC_OTLP_PROTOCOL" => config.otlp_protocol = value,
+                "CNPROC_PUSHGATEWAY" => config.pushgateway = Some(value),
+                "CNPROC_REMOTE_WRITE" => config.remote_write = Some(value),
+                "CNPROC_PUSH_INTERVAL" => config.push_interval = number(&key, &value).unwrap_or(config.push_interval),
+                "CNPROC_PUSH_BUFFER" => config.push_buffer = Some(value),
+                "CNPROC_PUSH_BUFFER_MAX" => config.push_buffer_max = number(&key, &value).unwrap_or(config.push_buffer_max),
+                "CNPROC_STATSD" => config.statsd = Some(value),
+                "CNPROC_RULES" => config.rules = Some(list(&value)),
+                "CNPROC_RULES_DISABLED" => config.rules_disabled = list(&value),
+                "CNPROC_WEBHOOK" => config.webhook = Some(value),
+                "CNPROC_WEBHOOK_RATE" => config.webhook_rate = number(&key, &value).unwrap_or(config.webhook_rate),
+                "CNPROC_WEBHOOK_DEDUP" => config.webhook_dedup = number(&key, &value).unwrap_or(config.webhook_dedup),
+                "CNPROC_SOCKET" => config.socket = Some(value),
+                "CNPROC_BASELINE" => config.baseline = Some(value),
+                "CNPROC_BASELINE_LEARN" => config.baseline_learn = number(&key, &value).unwrap_or(config.baseline_learn),
+                "CNPROC_STATE" => config.state = Some(value),
+                "CNPROC_STATE_INTERVAL" => config.state_interval = number(&key, &value).unwrap_or(config.state_interval),
+                "CNPROC_MINER_INTERVAL" => config.miner_interval = number(&key, &value).unwrap_or(config.miner_interval),
+                "CNPROC_MINER_THRESHOLD" => config.miner_threshold = number(&key, &value).unwrap_or(config.miner_threshold),
+                "CNPROC_REDACT" => config.redact = Some(value),
+                "CNPROC_ARGV_MAX" => config.argv_max = number(&key, &value).unwrap_or(config.argv_max),
+                "CNPROC_ENVIRON" => config.environ = list(&value),
+                _ => continue,
+            }
+        }
+        config
+    }
+}
+
+fn flag(key: &str, value: &str) -> bool {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => true,
+        "0" | "false" | "no" | "off" | "" => false,
+        _ => {
+            warn!("{}={} is not a boolean", key, value);
+            false
+        }
+    }
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn number<T: FromStr>(key: &str, value: &str) -> Option<T> {
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            warn!("{}={} is not a number", key, value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_vars(vars(&[("HOME", "/root")]));
+        assert!(!config.package_label);
+        assert_eq!("entropy", config.randomness);
+        assert_eq!(None, config.randomness_threshold);
+        assert_eq!(vec!["systemd"], config.skip);
+    }
+
+    #[test]
+    fn lists() {
+        let config = Config::from_vars(vars(&[("CNPROC_SKIP", "systemd, tini,,dumb-init")]));
+        assert_eq!(vec!["systemd", "tini", "dumb-init"], config.skip);
+    }
+
+    #[test]
+    fn flags() {
+        let config = Config::from_vars(vars(&[
+            ("CNPROC_PACKAGE_LABEL", "yes"),
+            ("CNPROC_RANDOMNESS", "ngram"),
+            ("CNPROC_RANDOMNESS_THRESHOLD", "2.5"),
+        ]));
+        assert!(config.package_label);
+        assert_eq!("ngram", config.randomness);
+        assert_eq!(Some(2.5), config.randomness_threshold);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::io::{Error, Result};
+use std::mem;
+use std::time::Duration;
+
+/// Kernel connector for process events, see `linux/cn_proc.h`
+const NETLINK_CONNECTOR: i32 = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_NOOP: u16 = 1;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const CN_MSG_LEN: usize = 20;
+
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_UID: u32 = 0x0000_0004;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+/// Process event, that is received from the kernel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcEvent {
+    Fork { parent: i32, pid: i32 },
+    Exec { pid: i32 },
+    Uid { pid: i32, ruid: u32, euid: u32 },
+    Exit { pid: i32, code: u32, signal: u32 },
+}
+
+/// Listens for process events on the netlink socket. Unlike `cnproc` crate,
+/// it also reports user changes and exit codes.
+#[derive(Debug)]
+pub struct Connector {
+    fd: libc::c_int,
+    queue: VecDeque<ProcEvent>,
+    buf: Vec<u8>,
+}
+
+impl Connector {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(libc::PF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, NETLINK_CONNECTOR)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let connector = Self{fd, queue: VecDeque::new(), buf: vec![0; 8192]};
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_pid = std::process::id();
+        addr.nl_groups = CN_IDX_PROC;
+        let bound = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as _,
+                mem::size_of::<libc::sockaddr_nl>() as _)
+        };
+        if bound < 0 {
+            return Err(Error::last_os_error());
+        }
+        connector.listen()?;
+        Ok(connector)
+    }
+
+    /// Makes receive return `WouldBlock` error, when there were no events for a while,
+    /// so that periodic work is done on idle machines as well
+    pub fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        let tv = libc::timeval{
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: timeout.subsec_micros() as _,
+        };
+        let set = unsafe {
+            libc::setsockopt(self.fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                &tv as *const libc::timeval as _, mem::size_of::<libc::timeval>() as _)
+        };
+        if set < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Signals to the kernel, that we're ready to receive events
+    fn listen(&self) -> Result<()> {
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + 4;
+        let mut msg = Vec::with_capacity(len);
+        // struct nlmsghdr
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&std::process::id().to_ne_bytes());
+        // struct cn_msg
+        msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&4u16.to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        // enum proc_cn_mcast_op
+        msg.extend_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+        let sent = unsafe { libc::send(self.fd, msg.as_ptr() as _, msg.len(), 0) };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until the next event is received
+    pub fn recv(&mut self) -> Result<ProcEvent> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Ok(event);
+            }
+            let len = unsafe {
+                libc::recv(self.fd, self.buf.as_mut_ptr() as _, self.buf.len(), 0)
+            };
+            if len < 0 {
+                return Err(Error::last_os_error());
+            }
+            parse(&self.buf[..len as usize], &mut self.queue);
+        }
+    }
+}
+
+impl Drop for Connector {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    buf.get(offset..offset + 2).map(|b| u16::from_ne_bytes([b[0], b[1]]))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Parses netlink messages with `struct proc_event` payloads
+fn parse(mut buf: &[u8], queue: &mut VecDeque<ProcEvent>) {
+    while buf.len() >= NLMSG_HDRLEN {
+        let msg_len = u32_at(buf, 0).unwrap_or(0) as usize;
+        let msg_type = u16_at(buf, 4).unwrap_or(NLMSG_ERROR);
+        if msg_len < NLMSG_HDRLEN || msg_len > buf.len() {
+            break;
+        }
+        if msg_type != NLMSG_NOOP && msg_type != NLMSG_ERROR {
+            if let Some(event) = proc_event(&buf[NLMSG_HDRLEN..msg_len]) {
+                queue.push_back(event);
+            }
+        }
+        // NLMSG_ALIGN
+        let aligned = (msg_len + 3) & !3;
+        buf = buf.get(aligned..).unwrap_or(&[]);
+    }
+}
+
+fn proc_event(msg: &[u8]) -> Option<ProcEvent> {
+    if u32_at(msg, 0)? != CN_IDX_PROC || u32_at(msg, 4)? != CN_VAL_PROC {
+        return None;
+    }
+    // what, cpu and timestamp_ns go before event_data
+    let ev = msg.get(CN_MSG_LEN..)?;
+    let data = 16;
+    let field = |n: usize| u32_at(ev, data + n * 4);
+    match u32_at(ev, 0)? {
+        PROC_EVENT_FORK => {
+            let (parent, pid, tgid) = (field(1)?, field(2)?, field(3)?);
+            if pid != tgid {
+                // threads are not interesting
+                return None;
+            }
+            Some(ProcEvent::Fork{parent: parent as i32, pid: pid as i32})
+        }
+        PROC_EVENT_EXEC => Some(ProcEvent::Exec{pid: field(0)? as i32}),
+        PROC_EVENT_UID => Some(ProcEvent::Uid{
+            pid: field(0)? as i32,
+            ruid: field(2)?,
+            euid: field(3)?,
+        }),
+        PROC_EVENT_EXIT => Some(ProcEvent::Exit{
+            pid: field(0)? as i32,
+            code: field(2)?,
+            signal: field(3)?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(what: u32, data: &[u32]) -> Vec<u8> {
+        let mut ev = vec![];
+        ev.extend_from_slice(&what.to_ne_bytes());
+        ev.extend_from_slice(&0u32.to_ne_bytes());
+        ev.extend_from_slice(&0u64.to_ne_bytes());
+        for d in data {
+            ev.extend_from_slice(&d.to_ne_bytes());
+        }
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + ev.len();
+        let mut msg = vec![];
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        msg.extend_from_slice(&[0; 8]);
+        msg.extend_from_slice(&(ev.len() as u16).to_ne_bytes());
+        msg.extend_from_slice(&[0; 2]);
+        msg.extend_from_slice(&ev);
+        msg
+    }
+
+    #[test]
+    fn parses_events() {
+        let mut buf = message(PROC_EVENT_FORK, &[10, 10, 11, 11]);
+        buf.extend(message(PROC_EVENT_FORK, &[11, 11, 12, 11]));
+        buf.extend(message(PROC_EVENT_EXEC, &[11, 11]));
+        buf.extend(message(PROC_EVENT_UID, &[11, 11, 1000, 0]));
+        buf.extend(message(PROC_EVENT_EXIT, &[11, 11, 256, 17, 10, 10]));
+        let mut queue = VecDeque::new();
+        parse(&buf, &mut queue);
+        assert_eq!(vec![
+            ProcEvent::Fork{parent: 10, pid: 11},
+            ProcEvent::Exec{pid: 11},
+            ProcEvent::Uid{pid: 11, ruid: 1000, euid: 0},
+            ProcEvent::Exit{pid: 11, code: 256, signal: 17},
+        ], queue.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[ignore]
+    fn receives_exec() {
+        // requires root and the initial network namespace
+        let mut connector = Connector::new().unwrap();
+        let mut child = std::process::Command::new("/bin/true").spawn().unwrap();
+        let pid = child.id() as i32;
+        child.wait().unwrap();
+        loop {
+            if let ProcEvent::Exec{pid: exec} = connector.recv().unwrap() {
+                if exec == pid {
+                    break;
+                }
+            }
+        }
+    }
+}
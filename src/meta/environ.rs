@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Folders, where the dynamic linker looks for libraries anyway
+const STANDARD: [&str; 9] = ["/lib", "/lib32", "/lib64", "/libx32", "/usr/lib", "/usr/lib32", "/usr/lib64",
+    "/usr/libx32", "/usr/local/lib"];
+
+/// Environment of the process at the moment of exec
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Environ {
+    /// Configured variables, that the process has
+    pub vars: BTreeMap<String, String>,
+    /// Libraries or folders from `LD_PRELOAD` and `LD_LIBRARY_PATH`, that are not in standard locations,
+    /// where `.` is the current folder
+    pub preload: Vec<String>,
+}
+
+impl Environ {
+    /// Reads `/proc/<pid>/environ`, that is readable by the owner of the process or root
+    pub fn read(pid: i32, keys: &[String]) -> Self {
+        match fs::read(format!("/proc/{}/environ", pid)) {
+            Ok(environ) => parse(&String::from_utf8_lossy(&environ), keys),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+fn parse(environ: &str, keys: &[String]) -> Environ {
+    let mut parsed = Environ::default();
+    for (key, value) in environ.split('\0').filter_map(|v| v.split_once('=')) {
+        if keys.iter().any(|k| k == key) {
+            parsed.vars.insert(String::from(key), String::from(value));
+        }
+        if key == "LD_LIBRARY_PATH" && !value.is_empty() {
+            // empty entry, like in `/opt/lib::`, is the current folder
+            for path in value.split([':', ';']) {
+                match path {
+                    "" => parsed.preload.push(String::from(".")),
+                    path if !is_standard(path) => parsed.preload.push(String::from(path)),
+                    _ => {}
+                }
+            }
+        } else if key == "LD_PRELOAD" {
+            // separated by spaces or colons, where empty entries load nothing
+            let paths = value.split([' ', ':']).filter(|p| !p.is_empty());
+            parsed.preload.extend(paths.filter(|p| !is_standard(p)).map(String::from));
+        }
+    }
+    parsed
+}
+
+/// Tells if the library is searched in standard folders or is inside one of them
+fn is_standard(path: &str) -> bool {
+    if !path.contains('/') {
+        return true;
+    }
+    let path = path.trim_end_matches('/');
+    STANDARD.iter().any(|s| path == *s || path.strip_prefix(s).is_some_and(|rest| rest.starts_with('/')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_allowed_keys() {
+        let keys = vec![String::from("SUDO_USER"), String::from("SSH_CONNECTION")];
+        let environ = parse("HOME=/root\0SUDO_USER=bob\0PATH=/usr/bin\0BROKEN\0", &keys);
+        assert_eq!(Some("bob"), environ.vars.get("SUDO_USER").map(String::as_str));
+        assert_eq!(1, environ.vars.len());
+        assert!(environ.preload.is_empty());
+        assert!(Environ::read(std::process::id() as i32, &[String::from("PATH")]).vars.contains_key("PATH"));
+    }
+
+    #[test]
+    fn finds_preloads() {
+        let environ = parse("LD_PRELOAD=libjemalloc.so.2 /usr/lib/x86_64-linux-gnu/libtcmalloc.so:/tmp/.x/libhide.so\0\
+            LD_LIBRARY_PATH=/usr/local/lib:/opt/app/lib/:/lib64;/usr/lib64/\0", &[]);
+        assert_eq!(vec!["/tmp/.x/libhide.so", "/opt/app/lib/"], environ.preload);
+        assert!(environ.vars.is_empty());
+        assert!(!is_standard("/usr/library/x.so"));
+        assert!(!is_standard("./libx.so"));
+    }
+
+    #[test]
+    fn empty_library_path_is_current_folder() {
+        let environ = parse("LD_LIBRARY_PATH=/opt/app/lib::/usr/lib:\0LD_PRELOAD= libx.so \0", &[]);
+        assert_eq!(vec!["/opt/app/lib", ".", "."], environ.preload);
+        assert!(parse("LD_LIBRARY_PATH=\0", &[]).preload.is_empty());
+    }
+}
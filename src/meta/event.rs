@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use super::info::Process;
+use super::usage::Usage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Exec,
+    Exit,
+    Fork,
+    Uid,
+    /// Tree, that is not in the baseline
+    Unexpected,
+    /// Exec, that matched a rule
+    Alert,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Exec => "exec",
+            Kind::Exit => "exit",
+            Kind::Fork => "fork",
+            Kind::Uid => "uid",
+            Kind::Unexpected => "unexpected",
+            Kind::Alert => "alert",
+        }
+    }
+
+    /// Syslog severity: warning for unexpected trees and alerts, informational otherwise
+    pub fn severity(&self) -> u8 {
+        match self {
+            Kind::Unexpected | Kind::Alert => 4,
+            _ => 6,
+        }
+    }
+}
+
+/// Record about a single process event, that is sent to sinks
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub kind: Kind,
+    pub time: String,
+    pub pid: i32,
+    pub ppid: i32,
+    pub tree: String,
+    pub exe: String,
+    pub argv: Vec<String>,
+    pub user: String,
+    pub uid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// Class of the folder with the executed file, like `tmp` or `home`
+    pub location: &'static str,
+    /// Setuid, setgid or file capabilities of the executed binary
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub privileges: Vec<&'static str>,
+    /// Effective capabilities mask in hex, like in `/proc/<pid>/status`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<String>,
+    /// Configured environment variables at the moment of exec
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub environ: BTreeMap<String, String>,
+    /// Libraries and folders from `LD_PRELOAD` and `LD_LIBRARY_PATH` in non-standard locations
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preload: Vec<String>,
+    /// When the process was discovered
+    pub started: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_signal: Option<u32>,
+    /// Hash of the executed binary, that is known once it's hashed in the background,
+    /// so the first exec of a new binary has it only in the following events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Hash of the script, that is run by interpreter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_sha256: Option<String>,
+    /// Resources, that the process has used until exit. Peak memory and I/O
+    /// are best-effort, as they may be gone or come from the last snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Name of the matched rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<&'static str>,
+    /// Miner suspicion score from 0 to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+impl Event {
+    pub fn new(kind: Kind, prc: &Process) -> Self {
+        Self{
+            kind,
+            time: rfc3339(SystemTime::now()),
+            pid: prc.pid,
+            ppid: prc.ppid,
+            tree: prc.tree.clone(),
+            exe: String::from(prc.exe()),
+            argv: prc.argv.clone(),
+            user: prc.user(),
+            uid: prc.uid,
+            cwd: prc.cwd.clone(),
+            container: prc.container.clone(),
+            location: prc.location.as_str(),
+            privileges: prc.privileges.kinds(),
+            capabilities: prc.privileges.effective.map(|caps| format!("{:016x}", caps)),
+            environ: prc.environ.vars.clone(),
+            preload: prc.environ.preload.clone(),
+            started: rfc3339(prc.started),
+            duration: None,
+            exit_code: None,
+            exit_signal: None,
+            sha256: prc.hashes.sha256.clone(),
+            script_sha256: prc.hashes.script_sha256.clone(),
+            usage: None,
+            rule: None,
+            score: None,
+        }
+    }
+}
+
+/// Formats time as `2021-06-01T12:34:56.789Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+        rem / 3600, rem % 3600 / 60, rem % 60, since.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_time() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_622_550_896_789);
+        assert_eq!("2021-06-01T12:34:56.789Z", rfc3339(time));
+        assert_eq!("1970-01-01T00:00:00.000Z", rfc3339(UNIX_EPOCH));
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!("2000-02-29T00:00:00.000Z", rfc3339(leap));
+    }
+
+    #[test]
+    fn serializes() {
+        let mut prc = Process::from(3, 2, "/usr/bin/top", vec![String::from("top")]);
+        prc.tree = String::from("/sshd/bash/top");
+        let mut event = Event::new(Kind::Exit, &prc);
+        event.exit_code = Some(0);
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!("exit", json["kind"]);
+        assert_eq!("/sshd/bash/top", json["tree"]);
+        assert_eq!(0, json["exit_code"]);
+        assert!(json.get("cwd").is_none());
+    }
+}
@@ -0,0 +1,303 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use hpack::Decoder;
+use super::http;
+
+/// Client connection preface of HTTP/2 without TLS, that is what gRPC uses for `http://`
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+/// Initial flow control window of every connection and stream
+const WINDOW: i64 = 65535;
+/// Default maximum frame size, that both sides accept
+const MAX_FRAME: usize = 16384;
+/// Every call has its own connection, so it's always the first stream
+const STREAM: u32 = 1;
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Frame> {
+    let mut head = [0u8; 9];
+    reader.read_exact(&mut head)?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    if len > MAX_FRAME {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+    Ok(Frame{kind: head[3], flags: head[4], stream, payload})
+}
+
+fn write_frame(writer: &mut impl Write, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)
+}
+
+/// Encodes HPACK integer with the prefix of `bits` in the first octet
+fn integer(out: &mut Vec<u8>, first: u8, bits: u32, mut value: usize) {
+    let max = (1 << bits) - 1;
+    if value < max {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Encodes headers as HPACK literals without indexing and Huffman coding,
+/// so that the dynamic table of the server is never used
+fn header_block(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in headers {
+        block.push(0);
+        integer(&mut block, 0, 7, name.len());
+        block.extend_from_slice(name.as_bytes());
+        integer(&mut block, 0, 7, value.len());
+        block.extend_from_slice(value.as_bytes());
+    }
+    block
+}
+
+/// Client side of HTTP/2 connection with a single stream
+struct Connection {
+    stream: TcpStream,
+    decoder: Decoder<'static>,
+    /// Bytes, that the server allows us to send on the connection and on the stream
+    connection_window: i64,
+    stream_window: i64,
+    initial_window: i64,
+    /// Header block, that may continue in CONTINUATION frames
+    block: Vec<u8>,
+    block_ends_stream: bool,
+    /// Response headers and trailers
+    headers: Vec<(String, String)>,
+    ended: bool,
+}
+
+impl Connection {
+    fn receive(&mut self) -> Result<()> {
+        let frame = read_frame(&mut self.stream)?;
+        match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => {
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]) as i64;
+                    if id == SETTINGS_INITIAL_WINDOW_SIZE {
+                        self.stream_window += value - self.initial_window;
+                        self.initial_window = value;
+                    }
+                }
+                write_frame(&mut self.stream, SETTINGS, ACK, 0, &[])?;
+            }
+            PING if frame.flags & ACK == 0 => write_frame(&mut self.stream, PING, ACK, 0, &frame.payload)?,
+            WINDOW_UPDATE if frame.payload.len() == 4 => {
+                let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2],
+                    frame.payload[3]]) & 0x7fff_ffff) as i64;
+                match frame.stream {
+                    0 => self.connection_window += increment,
+                    _ => self.stream_window += increment,
+                }
+            }
+            HEADERS if frame.stream == STREAM => {
+                let mut payload = &frame.payload[..];
+                if frame.flags & PADDED != 0 {
+                    let padding = *payload.first().unwrap_or(&0) as usize;
+                    payload = payload.get(1..payload.len().saturating_sub(padding)).unwrap_or_default();
+                }
+                if frame.flags & PRIORITY != 0 {
+                    payload = payload.get(5..).unwrap_or_default();
+                }
+                self.block = payload.to_vec();
+                self.block_ends_stream = frame.flags & END_STREAM != 0;
+                self.end_headers(frame.flags)?;
+            }
+            CONTINUATION if frame.stream == STREAM => {
+                self.block.extend_from_slice(&frame.payload);
+                self.end_headers(frame.flags)?;
+            }
+            DATA if frame.stream == STREAM => self.ended = frame.flags & END_STREAM != 0,
+            RST_STREAM if frame.stream == STREAM => {
+                return Err(Error::other(format!("stream reset with code {:?}", frame.payload)));
+            }
+            GOAWAY => return Err(Error::other("connection closed by the server")),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn end_headers(&mut self, flags: u8) -> Result<()> {
+        if flags & END_HEADERS == 0 {
+            return Ok(());
+        }
+        let headers = self.decoder.decode(&self.block)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("cannot decode headers: {:?}", e)))?;
+        for (name, value) in headers {
+            self.headers.push((String::from_utf8_lossy(&name).into(), String::from_utf8_lossy(&value).into()));
+        }
+        self.ended = self.block_ends_stream;
+        Ok(())
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Calls unary gRPC method, like `/opentelemetry.proto.collector.logs.v1.LogsService/Export`,
+/// over HTTP/2 without TLS. Response message is not needed, so only the status is checked.
+pub fn call(endpoint: &str, method: &str, message: &[u8]) -> Result<()> {
+    let url = http::parse(endpoint)?;
+    let mut conn = Connection{stream: url.connect()?, decoder: Decoder::new(), connection_window: WINDOW,
+        stream_window: WINDOW, initial_window: WINDOW, block: vec![], block_ends_stream: false,
+        headers: vec![], ended: false};
+    conn.stream.write_all(PREFACE)?;
+    write_frame(&mut conn.stream, SETTINGS, 0, 0, &[])?;
+    let authority = url.authority();
+    let block = header_block(&[(":method", "POST"), (":scheme", "http"), (":path", method),
+        (":authority", &authority), ("content-type", "application/grpc"), ("te", "trailers"),
+        ("user-agent", "prom-cnproc")]);
+    write_frame(&mut conn.stream, HEADERS, END_HEADERS, STREAM, &block)?;
+
+    // uncompressed length-prefixed message
+    let mut body = vec![0];
+    body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    body.extend_from_slice(message);
+    let mut sent = 0;
+    while sent < body.len() {
+        let window = conn.connection_window.min(conn.stream_window);
+        if window <= 0 {
+            conn.receive()?;
+            continue;
+        }
+        let len = (body.len() - sent).min(MAX_FRAME).min(window as usize);
+        let flags = if sent + len == body.len() { END_STREAM } else { 0 };
+        write_frame(&mut conn.stream, DATA, flags, STREAM, &body[sent..sent + len])?;
+        conn.connection_window -= len as i64;
+        conn.stream_window -= len as i64;
+        sent += len;
+    }
+    while !conn.ended {
+        conn.receive()?;
+    }
+    if conn.header(":status") != Some("200") {
+        return Err(Error::other(format!("{} responded with {}", endpoint, conn.header(":status").unwrap_or("nothing"))));
+    }
+    match conn.header("grpc-status") {
+        Some("0") => Ok(()),
+        Some(status) => Err(Error::other(format!("{} responded with grpc-status {}: {}", endpoint, status,
+            conn.header("grpc-message").unwrap_or_default()))),
+        None => Err(Error::new(ErrorKind::InvalidData, format!("{} sent no grpc-status", endpoint))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Accepts gRPC calls on the random local port, responds with `grpc-status`
+    /// and passes method and message to the receiver. Window is replenished
+    /// only after the first frames, so that the client has to wait for it.
+    pub fn server(status: u8) -> (String, Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut preface = [0; 24];
+                stream.read_exact(&mut preface).unwrap();
+                assert_eq!(PREFACE, &preface[..]);
+                // smaller window than the default one
+                write_frame(&mut stream, SETTINGS, 0, 0, &[0, 4, 0, 0, 0x40, 0]).unwrap();
+                let mut decoder = Decoder::new();
+                let mut method = String::new();
+                let mut body = vec![];
+                loop {
+                    let frame = read_frame(&mut stream).unwrap();
+                    if frame.kind == HEADERS {
+                        let headers = decoder.decode(&frame.payload).unwrap();
+                        let path = headers.iter().find(|(n, _)| n == b":path").unwrap();
+                        method = String::from_utf8(path.1.clone()).unwrap();
+                    }
+                    if frame.kind == DATA {
+                        body.extend_from_slice(&frame.payload);
+                        let increment = (frame.payload.len() as u32).to_be_bytes();
+                        write_frame(&mut stream, WINDOW_UPDATE, 0, 0, &increment).unwrap();
+                        write_frame(&mut stream, WINDOW_UPDATE, 0, STREAM, &increment).unwrap();
+                        if frame.flags & END_STREAM != 0 {
+                            break;
+                        }
+                    }
+                }
+                let headers = header_block(&[(":status", "200"), ("content-type", "application/grpc")]);
+                write_frame(&mut stream, HEADERS, END_HEADERS, STREAM, &headers).unwrap();
+                write_frame(&mut stream, DATA, 0, STREAM, &[0, 0, 0, 0, 0]).unwrap();
+                let trailers = header_block(&[("grpc-status", &status.to_string()), ("grpc-message", "whatever")]);
+                write_frame(&mut stream, HEADERS, END_HEADERS | END_STREAM, STREAM, &trailers).unwrap();
+                if tx.send((method, body[5..].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn encodes_integers() {
+        let mut out = vec![];
+        integer(&mut out, 0, 7, 10);
+        integer(&mut out, 0, 5, 1337);
+        assert_eq!(vec![10, 31, 154, 10], out);
+        let block = header_block(&[("te", "trailers")]);
+        assert_eq!(vec![(b"te".to_vec(), b"trailers".to_vec())], Decoder::new().decode(&block).unwrap());
+    }
+
+    #[test]
+    fn calls_methods() {
+        let (url, rx) = server(0);
+        // doesn't fit into a single frame nor into the initial window
+        let message = vec![7; 100_000];
+        call(&url, "/test.Service/Export", &message).unwrap();
+        let (method, received) = rx.recv().unwrap();
+        assert_eq!("/test.Service/Export", method);
+        assert_eq!(message, received);
+
+        let (url, _rx) = server(14);
+        let err = call(&url, "/test.Service/Export", b"").unwrap_err();
+        assert!(err.to_string().contains("grpc-status 14: whatever"), "{}", err);
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Result};
+use std::os::linux::fs::MetadataExt;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use log::*;
+use metrics::{gauge, increment_counter};
+use sha2::{Digest, Sha256};
+use super::info::Process;
+
+/// Maximum number of files waiting to be hashed
+const QUEUE_SIZE: usize = 1024;
+
+/// Maximum number of `process_exe_info` series, as they're never removed
+const INFO_SERIES: usize = 4096;
+
+/// Maximum number of known hashes, after which they're forgotten and computed again
+const DONE_SIZE: usize = 16384;
+
+/// Identifies file contents without reading them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl From<&Metadata> for Key {
+    fn from(meta: &Metadata) -> Self {
+        Key{
+            dev: meta.st_dev(),
+            ino: meta.st_ino(),
+            mtime: meta.st_mtime(),
+            mtime_nsec: meta.st_mtime_nsec(),
+        }
+    }
+}
+
+struct Job {
+    file: File,
+    path: String,
+    key: Key,
+}
+
+/// Hashes of files and files, that are queued for hashing
+#[derive(Default)]
+struct Hashes {
+    done: HashMap<Key, String>,
+    pending: HashSet<Key>,
+    /// Number of `process_exe_info` series so far
+    series: usize,
+}
+
+impl Hashes {
+    fn insert(&mut self, key: Key, hash: String) {
+        if self.done.len() >= DONE_SIZE {
+            // churning binaries, like on CI runners, would grow it forever
+            self.done.clear();
+        }
+        self.done.insert(key, hash);
+    }
+}
+
+/// Hashes of the executed binary and the script, that are filled in,
+/// once the background thread is done with them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileHashes {
+    exe: Option<Key>,
+    script: Option<Key>,
+    pub sha256: Option<String>,
+    pub script_sha256: Option<String>,
+}
+
+type Cache = Arc<Mutex<Hashes>>;
+
+/// Computes SHA-256 of executed files in the background thread
+pub struct Hasher {
+    cache: Cache,
+    queue: SyncSender<Job>,
+}
+
+impl Hasher {
+    pub fn new() -> Result<Self> {
+        let cache: Cache = Arc::new(Mutex::new(Hashes::default()));
+        let (queue, jobs) = sync_channel::<Job>(QUEUE_SIZE);
+        let shared = cache.clone();
+        thread::Builder::new()
+            .name(String::from("prom-cnproc-hasher"))
+            .spawn(move || {
+                for job in jobs {
+                    hash_job(&shared, job);
+                }
+            })?;
+        Ok(Self{cache, queue})
+    }
+
+    /// Schedules hashing of the executed binary and the script, if it's run by interpreter,
+    /// and returns hashes, that are already known
+    pub fn hash(&self, prc: &Process) -> FileHashes {
+        // deleted and memfd binaries are readable only through procfs
+        let exe = self.submit(prc.exe(), Path::new(&format!("/proc/{}/exe", prc.pid)));
+        let runnable = prc.actual_runnable();
+        let script = match runnable != prc.exe() {
+            true => self.submit(runnable, Path::new(runnable)),
+            false => None,
+        };
+        let mut hashes = FileHashes{exe, script, sha256: None, script_sha256: None};
+        self.fill(&mut hashes);
+        hashes
+    }
+
+    /// Fills in hashes, that were computed since the exec
+    pub fn fill(&self, hashes: &mut FileHashes) {
+        let done = &self.cache.lock().unwrap().done;
+        if hashes.sha256.is_none() {
+            hashes.sha256 = hashes.exe.and_then(|key| done.get(&key).cloned());
+        }
+        if hashes.script_sha256.is_none() {
+            hashes.script_sha256 = hashes.script.and_then(|key| done.get(&key).cloned());
+        }
+    }
+
+    /// Schedules hashing of the file, unless it's contents were hashed or queued before.
+    /// File is opened right away, because short-lived processes may be gone
+    /// and deleted binaries are reachable only through `/proc/<pid>/exe`.
+    fn submit(&self, path: &str, open: &Path) -> Option<Key> {
+        let key = match fs::metadata(open) {
+            Ok(meta) => Key::from(&meta),
+            Err(e) => {
+                debug!("cannot stat {} for hashing: {}", path, e);
+                return None;
+            }
+        };
+        {
+            // burst of execs of the same binary keeps only one file open
+            let mut hashes = self.cache.lock().unwrap();
+            if hashes.done.contains_key(&key) || !hashes.pending.insert(key) {
+                return Some(key);
+            }
+        }
+        let file = match File::open(open) {
+            Ok(file) => file,
+            Err(e) => {
+                debug!("cannot open {} for hashing: {}", path, e);
+                self.cache.lock().unwrap().pending.remove(&key);
+                return None;
+            }
+        };
+        let job = Job{file, path: String::from(path), key};
+        if let Err(TrySendError::Full(job)) = self.queue.try_send(job) {
+            warn!("hashing queue is full, skipping {}", job.path);
+            increment_counter!("process_hash_dropped_total");
+            self.cache.lock().unwrap().pending.remove(&job.key);
+            return None;
+        }
+        Some(key)
+    }
+}
+
+fn hash_job(cache: &Cache, mut job: Job) {
+    let hash = sha256(&mut job.file);
+    let mut hashes = cache.lock().unwrap();
+    hashes.pending.remove(&job.key);
+    let hash = match hash {
+        Ok(hash) => hash,
+        Err(e) => {
+            debug!("cannot hash {}: {}", job.path, e);
+            return;
+        }
+    };
+    info!("hashed path={} sha256={}", job.path, hash);
+    if hashes.series < INFO_SERIES {
+        hashes.series += 1;
+        gauge!("process_exe_info", 1.0, "path" => job.path, "sha256" => hash.clone());
+    } else {
+        increment_counter!("process_exe_info_dropped_total");
+    }
+    hashes.insert(job.key, hash);
+}
+
+/// Returns hex-encoded SHA-256 of everything in the reader
+fn sha256(reader: &mut impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn sha256_hex() {
+        let hash = sha256(&mut "abc".as_bytes()).unwrap();
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", hash);
+    }
+
+    #[test]
+    fn hashes_once() {
+        let hasher = Hasher::new().unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let key = hasher.submit("/test/exe", &exe);
+        assert_eq!(key, hasher.submit("/test/exe", &exe));
+
+        let start = Instant::now();
+        while hasher.cache.lock().unwrap().done.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        hasher.submit("/test/exe", &exe);
+        let hashes = hasher.cache.lock().unwrap();
+        assert_eq!(1, hashes.done.len());
+        assert!(hashes.pending.is_empty());
+        drop(hashes);
+        let mut hashes = FileHashes{exe: key, ..FileHashes::default()};
+        hasher.fill(&mut hashes);
+        assert_eq!(64, hashes.sha256.unwrap().len());
+        assert_eq!(None, hashes.script_sha256);
+    }
+
+    #[test]
+    fn forgets_when_full() {
+        let mut hashes = Hashes::default();
+        for ino in 0..=DONE_SIZE as u64 {
+            hashes.insert(Key{dev: 1, ino, mtime: 0, mtime_nsec: 0}, String::new());
+        }
+        assert_eq!(1, hashes.done.len());
+    }
+}
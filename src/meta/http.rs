@@ -0,0 +1,161 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parts of `http://host:port/path` URL, where IPv6 host is in brackets, like `http://[::1]:4318`
+#[derive(Debug, PartialEq)]
+pub struct Url<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+impl Url<'_> {
+    /// Returns `host:port` for `Host` header, with brackets around IPv6 address
+    pub fn authority(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    /// Opens connection with read and write timeouts
+    pub fn connect(&self) -> Result<TcpStream> {
+        let addr = (self.host, self.port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("cannot resolve {}", self.host)))?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+pub fn parse(url: &str) -> Result<Url<'_>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("{} is not http:// URL", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(ipv6) => match ipv6.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+            None => return Err(invalid()),
+        },
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => 80,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Url{host, port, path})
+}
+
+/// Sends request with the body over plain HTTP/1.1 and returns response status.
+/// Responses other than 2xx are errors. TLS is not supported.
+pub fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+    let parsed = parse(url)?;
+    let mut stream = parsed.connect()?;
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: prom-cnproc\r\n\
+        Content-Length: {}\r\nConnection: close\r\n",
+        method, parsed.path, parsed.authority(), body.len());
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    // HTTP/1.1 200 OK
+    let code: u16 = status.split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} sent invalid response", url)))?;
+    if !(200..300).contains(&code) {
+        return Err(Error::other(format!("{} responded with {}", url, status.trim())));
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Accepts requests on the random local port, responds with the status
+    /// and passes request line, headers and body to the receiver
+    pub fn server(status: u16) -> (String, Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let response = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                if tx.send((head, String::from_utf8_lossy(&body).to_string())).is_err() {
+                    break;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(Url{host: "collector", port: 4318, path: "/v1/logs"},
+            parse("http://collector:4318/v1/logs").unwrap());
+        assert_eq!(Url{host: "localhost", port: 80, path: "/"}, parse("http://localhost").unwrap());
+        assert!(parse("https://localhost").is_err());
+        assert!(parse("http://:80/").is_err());
+        let ipv6 = parse("http://[::1]:4318/v1/metrics").unwrap();
+        assert_eq!(Url{host: "::1", port: 4318, path: "/v1/metrics"}, ipv6);
+        assert_eq!("[::1]:4318", ipv6.authority());
+        assert_eq!(Url{host: "fe80::1", port: 80, path: "/"}, parse("http://[fe80::1]").unwrap());
+        assert!(parse("http://[::1:4318/").is_err());
+        assert!(parse("http://[::1]4318/").is_err());
+    }
+
+    #[test]
+    fn posts_body() {
+        let (url, rx) = server(202);
+        let status = request("POST", &format!("{}/hook", url), &[("Content-Type", "text/plain")], b"hello").unwrap();
+        let (head, body) = rx.recv().unwrap();
+        assert_eq!(202, status);
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: text/plain\r\n"));
+        assert_eq!("hello", body);
+
+        let (url, _rx) = server(500);
+        assert!(request("POST", &url, &[], b"").is_err());
+    }
+}
@@ -5,14 +5,22 @@ use std::fs::File;
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::io::Result;
-use std::time::Instant;
-use entropy::metric_entropy;
-use super::known::is_base;
+use std::io::{ErrorKind, Result};
+use std::time::{Instant, SystemTime};
+use super::environ::Environ;
+use super::hasher::FileHashes;
+use super::location::{self, Location};
+use super::namespaces::Namespaces;
+use super::packages::{self, is_base};
+use super::privileges::Privileges;
+use super::randomness::{self, RandomnessDetector};
+use super::usage::Usage;
 use log::trace;
 use std::collections::HashSet;
 use lazy_static::lazy_static;
 
+const INTERPRETERS: [&str; 6] = ["python", "perl", "ruby", "php", "lua", "node"];
+
 lazy_static! {
     static ref PYTHONS: HashSet<&'static str> = {
         let mut pythons = HashSet::new();
@@ -55,13 +63,62 @@ lazy_static! {
     };
 }
 
+/// Describes what is behind the executed binary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backing {
+    File,
+    /// binary was removed from disk right after it was started
+    Deleted,
+    /// binary was loaded from anonymous memory file
+    Memfd,
+}
+
+impl Backing {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backing::File => "file",
+            Backing::Deleted => "deleted",
+            Backing::Memfd => "memfd",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Process {
     pub pid: i32,
     pub ppid: i32,
     pub argv: Vec<String>,
     exe: PathBuf,
+    pub backing: Backing,
     pub start: Instant,
+    /// Wall clock time, when the process was discovered
+    pub started: SystemTime,
+    /// Effective user id, that is updated on setuid
+    pub uid: u32,
+    /// Working directory at the moment of exec
+    pub cwd: Option<String>,
+    /// Short id of the container, that runs this process
+    pub container: Option<String>,
+    /// Short label, that is cached once the process is discovered
+    pub label: String,
+    /// Full tree, that is cached once the process is discovered
+    pub tree: String,
+    /// When the process has started `curl` or `wget` for the last time
+    pub downloaded: Option<Instant>,
+    /// Class of the folder with the actual runnable file
+    pub location: Location,
+    pub privileges: Privileges,
+    pub namespaces: Namespaces,
+    /// How namespaces differ from the parent or the replaced image, see `namespaces::change`
+    pub namespace_change: Option<&'static str>,
+    /// Last snapshot of used resources, that is taken periodically
+    pub usage: Option<Usage>,
+    /// Configured environment variables and unusual preloads, that are read on exec
+    pub environ: Environ,
+    /// Owner of the actual runnable file, that is looked up once on exec
+    package: String,
+    /// Hashes of the binary and the script, that are computed in the background
+    pub hashes: FileHashes,
 }
 
 fn cmdline(pid: i32) -> Result<Vec<String>> {
@@ -76,7 +133,7 @@ fn ppid(pid: i32) -> Result<i32> {
     let mut buf2 = String::new();
     let mut s = File::open(format!("/proc/{}/stat", pid))?;
     s.read_to_string(&mut buf2)?;
-    let ppid = match buf2.split_whitespace().into_iter()
+    let ppid = match buf2.split_whitespace()
         .collect::<Vec<&str>>().get(3) {
             Some(ppid) => (*ppid).parse().unwrap(),
             None => 0,
@@ -84,40 +141,146 @@ fn ppid(pid: i32) -> Result<i32> {
     Ok(ppid)
 }
 
+/// Returns start time of the process in clock ticks after boot,
+/// that tells apart processes with the same reused pid
+pub fn start_ticks(pid: i32) -> Option<u64> {
+    stat_fields(pid, &[21]).map(|f| f[0])
+}
+
+/// Reads numeric fields of `/proc/<pid>/stat` by their zero based index
+pub fn stat_fields(pid: i32, indexes: &[usize]) -> Option<Vec<u64>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm may have spaces and parentheses
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // pid and comm are before the parenthesis
+    indexes.iter().map(|i| fields.get(i - 2)?.parse().ok()).collect()
+}
+
+/// Number of clock ticks per second
+pub fn clock_ticks() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_CLK_TCK) as u64 }
+}
+
+/// Resolves the link to executed binary, that may point to
+/// `/tmp/payload (deleted)` or `/memfd:payload (deleted)`.
+/// Only the kernel knows, that the file is deleted, as binaries
+/// of other mount namespaces don't exist on the host.
+fn resolve_exe(link: PathBuf) -> Result<(PathBuf, Backing)> {
+    let raw = link.to_string_lossy();
+    if raw.starts_with("/memfd:") {
+        return Ok((link, Backing::Memfd));
+    }
+    if let Some(path) = raw.strip_suffix(" (deleted)") {
+        return Ok((PathBuf::from(path), Backing::Deleted));
+    }
+    match link.canonicalize() {
+        Ok(exe) => Ok((exe, Backing::File)),
+        // kernel has already resolved the link within the mount namespace of the process
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok((link, Backing::File)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Finds container id in cgroup paths, like `/docker/<id>`,
+/// `/kubepods/.../cri-containerd-<id>.scope` or `/system.slice/crio-<id>.scope`
+fn container_id(cgroup: &str) -> Option<String> {
+    for line in cgroup.lines() {
+        let path = line.splitn(3, ':').nth(2).unwrap_or("");
+        for segment in path.rsplit('/') {
+            let id = segment
+                .trim_end_matches(".scope")
+                .rsplit('-')
+                .next()
+                .unwrap_or("");
+            if id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Some(String::from(&id[..12]));
+            }
+        }
+    }
+    None
+}
+
+fn container(pid: i32) -> Option<String> {
+    let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    container_id(&cgroup)
+}
+
+fn cwd(pid: i32) -> Option<String> {
+    let cwd = fs::read_link(format!("/proc/{}/cwd", pid)).ok()?;
+    cwd.to_str().map(String::from)
+}
+
+/// Returns name of the user or `unknown`, if there's no such user
+pub fn username(uid: u32) -> String {
+    unsafe {
+        let pw = libc::getpwuid(uid);
+        if pw.is_null() {
+            return String::from("unknown");
+        }
+        let cstr = CStr::from_ptr((*pw).pw_name);
+        String::from(cstr.to_str().unwrap_or("unknown"))
+    }
+}
+
+/// Returns name of this machine
+pub fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    unsafe {
+        if libc::gethostname(buf.as_mut_ptr(), buf.len()) != 0 {
+            return String::from("-");
+        }
+        let cstr = CStr::from_ptr(buf.as_ptr());
+        String::from(cstr.to_str().unwrap_or("-"))
+    }
+}
+
 impl Process {
     pub fn new(pid: i32) -> Result<Self> {
         let start = Instant::now();
         let argv = cmdline(pid)?;
         let ppid = ppid(pid)?;
-        let exe = Path::new(&format!("/proc/{}/exe", pid)).read_link()?;
-        let exe = exe.canonicalize()?;
-        trace!("{} pid={} ppid={} took={:.2?}", 
-            exe.to_str().unwrap_or("..."), pid, ppid, start.elapsed());
-        Ok(Process{pid, ppid, argv, exe, start}) 
+        let link = Path::new(&format!("/proc/{}/exe", pid)).read_link()?;
+        let (exe, backing) = resolve_exe(link)?;
+        let uid = fs::metadata(format!("/proc/{}", pid))?.st_uid();
+        let cwd = cwd(pid);
+        let container = container(pid);
+        trace!("{} pid={} ppid={} backing={} took={:.2?}",
+            exe.to_str().unwrap_or("..."), pid, ppid, backing.as_str(), start.elapsed());
+        let mut prc = Process{pid, ppid, argv, exe, backing, start, started: SystemTime::now(),
+            uid, cwd, container, label: String::new(), tree: String::new(), downloaded: None,
+            location: Location::Other, privileges: Privileges::read(pid),
+            namespaces: Namespaces::read(&pid.to_string()), namespace_change: None, usage: None,
+            environ: Environ::default(), package: String::new(), hashes: FileHashes::default()};
+        if prc.backing == Backing::File {
+            prc.location = location::classify(prc.actual_runnable());
+        }
+        prc.package = packages::db().package(prc.actual_runnable());
+        Ok(prc)
     }
 
     #[cfg(test)]
     pub fn from(pid: i32, ppid: i32, exe: &str, argv: Vec<String>) -> Self {
-        Self {pid, ppid, exe: PathBuf::from(exe), argv, start: Instant::now()}
+        let mut prc = Self {pid, ppid, exe: PathBuf::from(exe), argv, backing: Backing::File, start: Instant::now(),
+            started: SystemTime::now(), uid: 0, cwd: None, container: None,
+            label: String::new(), tree: String::new(), downloaded: None, location: Location::Other,
+            privileges: Privileges::default(), namespaces: Namespaces::default(), namespace_change: None,
+            usage: None, environ: Environ::default(), package: String::new(), hashes: FileHashes::default()};
+        prc.package = packages::db().package(prc.actual_runnable());
+        prc
     }
 
-    /// Returns minimum metric entropy of any path element
-    pub fn entropy(&self) -> f32 {
-        let mut path_entropy = std::f32::MAX;
-        let actual = self.actual_runnable();
-        let mut elems = actual.split("/");
-        for chunk in &mut elems {
-            let entropy = metric_entropy(chunk.as_bytes());
-            trace!("entropy {}={}", chunk, entropy);
-            if entropy < path_entropy {
-                path_entropy = entropy;
-            }
-        }
-        path_entropy
+    /// Path to the executed binary
+    pub fn exe(&self) -> &str {
+        self.exe.to_str().unwrap_or("/")
+    }
+
+    /// File name of the executed binary
+    pub fn filename(&self) -> &str {
+        self.exe.file_name().and_then(|f| f.to_str()).unwrap_or("/")
     }
 
     /// Determines actual runnable file - binary or script
-    fn actual_runnable(&self) -> &str {
+    pub fn actual_runnable(&self) -> &str {
         let sh  = self.is_shell();
         let py  = self.is_python();
         let has_args = self.argv.len() > 1;
@@ -129,7 +292,7 @@ impl Process {
                 return maybe_script;
             }
         }
-        self.exe.to_str().unwrap_or("/")
+        self.exe()
     }
 
     fn is_python(&self) -> bool {
@@ -139,44 +302,66 @@ impl Process {
         }
     }
 
-    fn is_shell(&self) -> bool {
+    pub fn is_shell(&self) -> bool {
         match self.exe.to_str() {
             Some(path) => SHELLS.contains(path),
             None => false,
         }
     }
+
+    /// Shells and scripting languages, that are used for reverse shells
+    pub fn is_interpreter(&self) -> bool {
+        let name = self.filename();
+        // versioned names, like perl5.30 or php7.4
+        self.is_shell() || INTERPRETERS.iter().any(|i| name.starts_with(i))
+    }
+
+    /// Tells if standard input or output is connected to TCP or UDP socket.
+    /// Unix sockets don't count, as services have their output connected to journald.
+    pub fn has_socket_stdio(&self) -> bool {
+        let inodes: Vec<String> = (0..2)
+            .filter_map(|fd| fs::read_link(format!("/proc/{}/fd/{}", self.pid, fd)).ok())
+            .filter_map(|target| target.to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .map(String::from))
+            .collect();
+        if inodes.is_empty() {
+            return false;
+        }
+        // tables of the network namespace of the process, where inode is the 10th column
+        ["tcp", "tcp6", "udp", "udp6"].iter().any(|table| {
+            match fs::read_to_string(format!("/proc/{}/net/{}", self.pid, table)) {
+                Ok(sockets) => sockets.lines().skip(1)
+                    .filter_map(|line| line.split_whitespace().nth(9))
+                    .any(|inode| inodes.iter().any(|i| i == inode)),
+                Err(_) => false,
+            }
+        })
+    }
     
     /// Determines short label to include in process tree
-    pub fn label(&self) -> &str {
+    pub fn compute_label(&self, detector: &dyn RandomnessDetector) -> String {
+        if self.backing != Backing::File {
+            // there's no file to look at anymore
+            return String::from(self.backing.as_str());
+        }
         let path = self.actual_runnable();
         if is_base(path) {
             // base system may have plenty of scripts
-            return "base";
+            return String::from("base");
         }
-        // maybe this will be improved
-        let filename = path.split("/").last().unwrap_or("/");
-        filename
+        randomness::label(detector, path)
+    }
+
+    /// Returns name of the package, that installed the executed file
+    pub fn package(&self) -> &str {
+        &self.package
     }
 
     /// Returns owner name of this process
-    #[allow(dead_code)]
-    pub fn user(&self) -> &str {
-        let default = "unknown";
-        let path = &format!("/proc/{}", self.pid);
-        let task = Path::new(path);
-        match fs::metadata(task) {
-            Ok(meta) => {
-                let uid = meta.st_uid();
-                unsafe {
-                    let pw = libc::getpwuid(uid);
-                    let name_ptr = (*pw).pw_name;
-                    let cstr = CStr::from_ptr(name_ptr);
-                    let username = cstr.to_str().unwrap_or(default);
-                    username
-                }
-            }
-            Err(_) => default,
-        }
+    pub fn user(&self) -> String {
+        username(self.uid)
     }
 }
 
@@ -184,12 +369,13 @@ impl Process {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use super::super::randomness::Entropy;
 
     #[test]
     fn cmdline_parses() {
         let pid = std::process::id() as i32;
         let cmd = cmdline(pid).unwrap();
-        assert_eq!(true, cmd.len() > 1);
+        assert!(cmd.len() > 1);
     }
 
     #[test]
@@ -208,50 +394,56 @@ mod tests {
     }
 
     fn dummy_path(exe: &str) -> Process {
-        Process{
-            argv: vec![],
-            pid: 0,
-            ppid: 0,
-            start: Instant::now(),
-            exe: PathBuf::from(exe)
-        }
+        Process::from(0, 0, exe, vec![])
     }
 
     #[test]
     fn labels() {
         let t = dummy_path("/usr/bin/dd");
-        assert_eq!("base", t.label());
+        assert_eq!("base", t.compute_label(&Entropy::new(3.4)));
 
         let t = dummy_path("/usr/bin/dd-outer");
-        assert_eq!("dd-outer", t.label());
+        assert_eq!("dd-outer", t.compute_label(&Entropy::new(3.4)));
     }
 
     #[test]
-    fn entropies() {
-        let t = dummy_path("/tmp/target/debug/deps/prom_cnproc-0883569a23a4bd16");
-        assert_eq!(0.15128307, t.entropy());
+    fn shell_script_label() {
+        let p = Process::from(0, 0, "/bin/bash", vec![
+            String::from("sh"),
+            String::from("/etc/init.d/hwclock.sh"),
+            String::from("-a"),
+            String::from("-b"),
+        ]);
+        assert_eq!("hwclock.sh", p.compute_label(&Entropy::new(3.4)))
+    }
+
+    #[test]
+    fn fileless_exe() {
+        let (exe, backing) = resolve_exe(PathBuf::from("/memfd:payload (deleted)")).unwrap();
+        assert_eq!(Backing::Memfd, backing);
+        assert_eq!(PathBuf::from("/memfd:payload (deleted)"), exe);
 
-        let t = dummy_path("/tmp/target/debug/deps/prom_cnproc");
-        assert_eq!(0.24837805, t.entropy());
+        let (exe, backing) = resolve_exe(PathBuf::from("/tmp/x/payload (deleted)")).unwrap();
+        assert_eq!(Backing::Deleted, backing);
+        assert_eq!(PathBuf::from("/tmp/x/payload"), exe);
 
-        let t = dummy_path("/tmp/ZW50cm9weQo/any-shady-process");
-        assert_eq!(0.20322484, t.entropy());
+        // binary of a container
+        let (exe, backing) = resolve_exe(PathBuf::from("/usr/local/bin/surely-not-existing-binary")).unwrap();
+        assert_eq!(Backing::File, backing);
+        assert_eq!(PathBuf::from("/usr/local/bin/surely-not-existing-binary"), exe);
+
+        let mut t = dummy_path("/tmp/x/payload");
+        t.backing = Backing::Deleted;
+        assert_eq!("deleted", t.compute_label(&Entropy::new(3.4)));
     }
 
     #[test]
-    fn shell_script_label() {
-        let p = Process{
-            pid: 0,
-            ppid: 0,
-            start: Instant::now(),
-            argv: vec![
-                String::from("sh"),
-                String::from("/etc/init.d/hwclock.sh"),
-                String::from("-a"),
-                String::from("-b"),
-            ],
-            exe: PathBuf::from("/bin/bash")
-        };
-        assert_eq!("hwclock.sh", p.label())
+    fn container_ids() {
+        let id = "4a6d37a1d4a7b3c2e0f1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f7";
+        let docker = format!("0::/system.slice/docker-{}.scope", id);
+        assert_eq!(Some(String::from("4a6d37a1d4a7")), container_id(&docker));
+        let k8s = format!("12:pids:/kubepods/burstable/pod0f1e/cri-containerd-{}.scope\n1:name=systemd:/", id);
+        assert_eq!(Some(String::from("4a6d37a1d4a7")), container_id(&k8s));
+        assert_eq!(None, container_id("0::/user.slice/user-1000.slice/session-2.scope"));
     }
-}
\ No newline at end of file
+}
@@ -677,6 +677,7 @@ lazy_static! {
     };
 }
 
-pub fn is_base(binary: &str) -> bool {
-    BASE.contains(binary)
+/// Returns base system binaries, that were known at compile time
+pub fn compiled() -> impl Iterator<Item = &'static str> {
+    BASE.iter().copied()
 }
\ No newline at end of file
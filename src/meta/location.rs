@@ -0,0 +1,157 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+
+/// How long the list of mounts is cached
+const MOUNTS_TTL: Duration = Duration::from_secs(60);
+
+/// Class of the folder, where the runnable file is. Exec from `/dev/shm`
+/// is a stronger signal, than the random looking name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Shm,
+    Tmp,
+    VarTmp,
+    /// Mount with `noexec` option, so that the file is run by interpreter
+    Noexec,
+    /// Folder, that everyone can write to
+    WorldWritable,
+    Home,
+    Other,
+}
+
+impl Location {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Location::Shm => "shm",
+            Location::Tmp => "tmp",
+            Location::VarTmp => "var-tmp",
+            Location::Noexec => "noexec",
+            Location::WorldWritable => "world-writable",
+            Location::Home => "home",
+            Location::Other => "other",
+        }
+    }
+}
+
+struct Mounts {
+    read: Instant,
+    /// Mount points with `noexec` option
+    noexec: Vec<String>,
+    /// Mount points without it, so that nested mounts are handled
+    exec: Vec<String>,
+}
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Option<Mounts>> = Mutex::new(None);
+}
+
+/// Parses `/proc/self/mounts` into mount points with and without `noexec`
+fn parse(mounts: &str) -> (Vec<String>, Vec<String>) {
+    let mut noexec = vec![];
+    let mut exec = vec![];
+    for line in mounts.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        // spaces are escaped as octal codes
+        let point = fields[1].replace("\\040", " ");
+        match fields[3].split(',').any(|o| o == "noexec") {
+            true => noexec.push(point),
+            false => exec.push(point),
+        }
+    }
+    (noexec, exec)
+}
+
+/// Length of the mount point, if the path is on it
+fn mounted(path: &str, point: &str) -> Option<usize> {
+    let on = point == "/" || path == point || path.strip_prefix(point).is_some_and(|r| r.starts_with('/'));
+    on.then_some(point.len())
+}
+
+fn is_noexec(path: &str) -> bool {
+    let mut mounts = MOUNTS.lock().unwrap();
+    if mounts.as_ref().is_none_or(|m| m.read.elapsed() >= MOUNTS_TTL) {
+        let (noexec, exec) = parse(&fs::read_to_string("/proc/self/mounts").unwrap_or_default());
+        *mounts = Some(Mounts{read: Instant::now(), noexec, exec});
+    }
+    let mounts = mounts.as_ref().unwrap();
+    let closest = |points: &[String]| points.iter().filter_map(|p| mounted(path, p)).max();
+    match (closest(&mounts.noexec), closest(&mounts.exec)) {
+        (Some(noexec), Some(exec)) => noexec > exec,
+        (noexec, _) => noexec.is_some(),
+    }
+}
+
+fn is_world_writable(path: &str) -> bool {
+    let dir = match Path::new(path).parent() {
+        Some(dir) => dir,
+        None => return false,
+    };
+    match fs::metadata(dir) {
+        Ok(meta) => meta.permissions().mode() & 0o002 != 0,
+        Err(_) => false,
+    }
+}
+
+/// Classifies the folder of the file, well known ones go first
+pub fn classify(path: &str) -> Location {
+    if path.starts_with("/dev/shm/") {
+        Location::Shm
+    } else if path.starts_with("/tmp/") {
+        Location::Tmp
+    } else if path.starts_with("/var/tmp/") {
+        Location::VarTmp
+    } else if is_noexec(path) {
+        Location::Noexec
+    } else if is_world_writable(path) {
+        Location::WorldWritable
+    } else if path.starts_with("/home/") || path.starts_with("/root/") {
+        Location::Home
+    } else {
+        Location::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::TempDir;
+
+    #[test]
+    fn well_known() {
+        assert_eq!(Location::Shm, classify("/dev/shm/x"));
+        assert_eq!(Location::Tmp, classify("/tmp/.x/y"));
+        assert_eq!(Location::VarTmp, classify("/var/tmp/x"));
+        assert_eq!(Location::Other, classify("/usr/bin/top"));
+    }
+
+    #[test]
+    fn world_writable() {
+        // anything under /tmp is classified as Tmp before permissions are checked
+        let tmp = TempDir::under(&std::env::current_dir().unwrap().join("target"), "location");
+        let path = tmp.join("payload");
+        let path = path.to_str().unwrap();
+        assert_ne!(Location::WorldWritable, classify(path));
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(Location::WorldWritable, classify(path));
+    }
+
+    #[test]
+    fn noexec_mounts() {
+        let (noexec, exec) = parse("proc /proc proc rw,nosuid,nodev,noexec 0 0\n\
+            tmpfs /run/my\\040app tmpfs rw,nosuid,noexec 0 0\n\
+            /dev/sda1 / ext4 rw 0 0\n\
+            /dev/sda2 /run/my\\040app/bin ext4 rw 0 0\n");
+        assert_eq!(vec!["/proc", "/run/my app"], noexec);
+        assert_eq!(vec!["/", "/run/my app/bin"], exec);
+        assert_eq!(Some(11), mounted("/run/my app/x.sh", "/run/my app"));
+        assert_eq!(None, mounted("/run/my apps/x.sh", "/run/my app"));
+        assert_eq!(Some(1), mounted("/usr/bin/top", "/"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use metrics::gauge;
+use super::info::Process;
+use super::rules;
+use super::watcher::ancestry;
+
+/// Processes, that run longer than this, get extra score
+const LONG_RUNNING: Duration = Duration::from_secs(600);
+
+/// Weights of the score components, that add up to 1. The rest of them
+/// add up to 0.6, so busy CPU is required to reach the default threshold of 0.7.
+const CPU: f64 = 0.4;
+const LONG: f64 = 0.1;
+const RANDOM: f64 = 0.2;
+const UNPACKAGED: f64 = 0.15;
+const SERVER: f64 = 0.15;
+
+/// Tells how much the process looks like a crypto-miner, from 0 to 1.
+/// Miners usually are random named binaries, that run long and hot,
+/// sometimes dropped through vulnerable web application. Idle processes
+/// score 0 without looking at anything else.
+pub fn score(prc: &Process, ancestry: &[&Process], usage: f64) -> f64 {
+    if usage <= 0. {
+        return 0.;
+    }
+    let mut score = CPU * usage.clamp(0., 1.);
+    if prc.start.elapsed() >= LONG_RUNNING {
+        score += LONG;
+    }
+    if prc.tree.contains("{random}") {
+        score += RANDOM;
+    }
+    if prc.package() == "unpackaged" {
+        score += UNPACKAGED;
+    }
+    if ancestry.iter().any(|p| rules::is_server(p)) {
+        score += SERVER;
+    }
+    score
+}
+
+struct Sample {
+    /// CPU seconds in user and kernel mode
+    cpu: f64,
+    at: Instant,
+}
+
+/// Scores tracked processes by their CPU time, that is periodically
+/// read into their resource usage
+pub struct Miner {
+    threshold: f64,
+    samples: HashMap<i32, Sample>,
+    /// Trees with non-zero score, that are reset once they're gone
+    trees: HashSet<String>,
+    /// Processes, that have already crossed the threshold
+    reported: HashSet<i32>,
+}
+
+impl Miner {
+    pub fn new(threshold: f64) -> Self {
+        Self{threshold, samples: HashMap::new(), trees: HashSet::new(), reported: HashSet::new()}
+    }
+
+    /// Updates `process_miner_score` of every tree with the highest score of its processes
+    /// and returns processes, that have crossed the threshold since the last sample
+    pub fn sample(&mut self, pids: &HashMap<i32, Process>) -> Vec<(i32, f64)> {
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        let mut samples = HashMap::new();
+        let mut crossed = vec![];
+        for (pid, prc) in pids {
+            let sample = match prc.usage {
+                Some(usage) => Sample{cpu: usage.user + usage.system, at: Instant::now()},
+                None => continue,
+            };
+            if let Some(previous) = self.samples.get(pid) {
+                let elapsed = sample.at.duration_since(previous.at).as_secs_f64().max(0.001);
+                // reused pid has less CPU time, than the previous process
+                let cpu = (sample.cpu - previous.cpu).max(0.);
+                let score = match cpu {
+                    // ancestry of idle processes is not walked at all
+                    cpu if cpu > 0. => score(prc, &ancestry(pids, prc), cpu / elapsed),
+                    _ => 0.,
+                };
+                let tree = scores.entry(&prc.tree).or_default();
+                *tree = tree.max(score);
+                if score >= self.threshold && self.reported.insert(*pid) {
+                    crossed.push((*pid, score));
+                }
+            }
+            samples.insert(*pid, sample);
+        }
+        self.samples = samples;
+        self.reported.retain(|pid| pids.contains_key(pid));
+        for tree in &self.trees {
+            if !scores.contains_key(tree.as_str()) {
+                gauge!("process_miner_score", 0., "tree" => tree.clone());
+            }
+        }
+        for (tree, score) in &scores {
+            gauge!("process_miner_score", *score, "tree" => tree.to_string());
+        }
+        self.trees = scores.into_iter()
+            .filter(|(_, score)| *score > 0.)
+            .map(|(tree, _)| String::from(tree))
+            .collect();
+        crossed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::usage::Usage;
+
+    #[test]
+    fn scores() {
+        let nginx = Process::from(2, 1, "/usr/sbin/nginx", vec![]);
+        let mut prc = Process::from(3, 2, "/tmp/kdevtmpfsi", vec![]);
+        // package database of this machine may or may not know the file
+        let cold = score(&prc, &[], 0.);
+        prc.tree = String::from("/nginx/{random}");
+        let hot = score(&prc, &[&nginx], 1.);
+        assert!(hot - cold > 0.749, "{} {}", hot, cold);
+        assert!(score(&prc, &[&nginx], 4.) == hot);
+        assert_eq!(0., score(&prc, &[&nginx], 0.));
+    }
+
+    #[test]
+    fn requires_cpu() {
+        let threshold = super::super::config::Config::default().miner_threshold;
+        let nginx = Process::from(2, 1, "/usr/sbin/nginx", vec![]);
+        let mut prc = Process::from(3, 2, "/tmp/kdevtmpfsi", vec![]);
+        prc.tree = String::from("/nginx/{random}");
+        prc.start -= LONG_RUNNING;
+        // everything, but CPU, stays below the threshold
+        assert!(LONG + RANDOM + UNPACKAGED + SERVER < threshold);
+        assert!(score(&prc, &[&nginx], 0.01) < threshold);
+        assert!(score(&prc, &[&nginx], 1.) >= threshold);
+    }
+
+    #[test]
+    fn samples_cpu() {
+        let mut pids = HashMap::new();
+        let pid = std::process::id() as i32;
+        let mut prc = Process::new(pid).unwrap();
+        prc.tree = String::from("/cargo/{random}");
+        prc.usage = Usage::read(pid);
+        // only CPU usage of at least a quarter of the core crosses it
+        let threshold = score(&prc, &[], 0.) + CPU / 4.;
+        pids.insert(pid, prc);
+        let mut miner = Miner::new(threshold);
+        assert!(miner.sample(&pids).is_empty());
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(300) {
+            std::hint::black_box(started.elapsed());
+        }
+        pids.get_mut(&pid).unwrap().usage = Usage::read(pid);
+        let crossed = miner.sample(&pids);
+        assert_eq!(1, crossed.len());
+        assert!(miner.sample(&pids).is_empty(), "reported only once");
+    }
+}
@@ -1,3 +1,32 @@
+pub mod baseline;
+pub mod config;
 pub mod info;
 pub mod watcher;
-mod known;
\ No newline at end of file
+mod aggregator;
+mod alert;
+mod connector;
+mod environ;
+mod event;
+mod grpc;
+mod hasher;
+mod http;
+mod known;
+mod location;
+mod miner;
+mod namespaces;
+mod otlp;
+mod packages;
+mod privileges;
+mod push;
+mod randomness;
+mod recorder;
+mod redact;
+mod rules;
+mod sink;
+mod state;
+mod statsd;
+mod stream;
+mod syslog;
+#[cfg(test)]
+mod testing;
+mod usage;
\ No newline at end of file
@@ -0,0 +1,149 @@
+use std::fs;
+use lazy_static::lazy_static;
+use super::info::Process;
+
+/// Namespaces from `/proc/<pid>/ns`, that are compared between parent and child
+pub const NAMES: [&str; 7] = ["cgroup", "ipc", "mnt", "net", "pid", "user", "uts"];
+
+/// Parents, that create namespaces for containers
+const RUNTIMES: [&str; 9] = ["runc", "crun", "containerd-shim", "conmon", "dockerd", "docker-init",
+    "systemd-nspawn", "lxc-start", "bwrap"];
+
+/// Parents, that sandbox their children in some of the namespaces, like systemd
+/// does for services with `PrivateTmp`, `PrivateNetwork` or `ProtectHostname`
+const SANDBOXES: [(&str, &[&str]); 7] = [
+    ("systemd", &["ipc", "mnt", "net", "uts"]),
+    ("snap-confine", &["mnt"]),
+    ("chrome", &["net", "pid", "user"]),
+    ("chrome-sandbox", &["net", "pid", "user"]),
+    ("chromium", &["net", "pid", "user"]),
+    ("firefox", &["net", "pid", "user"]),
+    ("firefox-bin", &["net", "pid", "user"]),
+];
+
+/// Tools, that enter or create namespaces from the command line
+const TOOLS: [&str; 2] = ["nsenter", "unshare"];
+
+lazy_static! {
+    /// Namespaces of the exporter, that is expected to run on the host
+    static ref HOST: Namespaces = Namespaces::read("self");
+}
+
+/// Inode numbers of namespaces in the same order, as `NAMES`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespaces([Option<u64>; 7]);
+
+impl Namespaces {
+    pub fn read(pid: &str) -> Self {
+        let mut inodes = [None; 7];
+        for (i, name) in NAMES.iter().enumerate() {
+            inodes[i] = fs::read_link(format!("/proc/{}/ns/{}", pid, name))
+                .ok()
+                .and_then(|link| inode(&link.to_string_lossy()));
+        }
+        Self(inodes)
+    }
+
+    fn get(&self, name: &str) -> Option<u64> {
+        let i = NAMES.iter().position(|n| *n == name)?;
+        self.0[i]
+    }
+
+    /// Names of namespaces, that are known for both and differ
+    pub fn diff(&self, other: &Namespaces) -> Vec<&'static str> {
+        NAMES.iter().zip(self.0.iter().zip(other.0.iter()))
+            .filter(|(_, (a, b))| a.is_some() && b.is_some() && a != b)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// Parses `mnt:[4026531840]`
+fn inode(link: &str) -> Option<u64> {
+    link.split_once(":[")?.1.strip_suffix(']')?.parse().ok()
+}
+
+/// Tells how namespaces of the process have changed compared to its parent or replaced image,
+/// unless it's done by container runtime or sandboxing parent:
+/// `escape` when process of a container gets host mount or pid namespace,
+/// `nsenter` or `unshare` when these tools are used, and `other` otherwise.
+pub fn change(current: &Namespaces, parent: &Process) -> Option<&'static str> {
+    change_from(current, parent, &HOST)
+}
+
+fn change_from(current: &Namespaces, parent: &Process, host: &Namespaces) -> Option<&'static str> {
+    let changed = current.diff(&parent.namespaces);
+    if changed.is_empty() {
+        return None;
+    }
+    let name = parent.filename();
+    if RUNTIMES.iter().any(|r| name.starts_with(r)) {
+        return None;
+    }
+    let escaped = ["mnt", "pid"].iter().any(|ns| {
+        let host = host.get(ns);
+        host.is_some() && parent.namespaces.get(ns) != host && current.get(ns) == host
+    });
+    if escaped {
+        return Some("escape");
+    }
+    let sandboxed = SANDBOXES.iter()
+        .any(|(sandbox, allowed)| *sandbox == name && changed.iter().all(|ns| allowed.contains(ns)));
+    if sandboxed {
+        return None;
+    }
+    Some(TOOLS.iter().find(|t| **t == name).copied().unwrap_or("other"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(exe: &str, inodes: [u64; 7]) -> Process {
+        let mut prc = Process::from(2, 1, exe, vec![]);
+        prc.namespaces = Namespaces(inodes.map(Some));
+        prc
+    }
+
+    #[test]
+    fn reads_namespaces() {
+        assert_eq!(Some(4026531840), inode("mnt:[4026531840]"));
+        assert_eq!(None, inode("mnt"));
+        assert!(Namespaces::read("self").get("mnt").is_some());
+        assert!(HOST.diff(&Namespaces::read(&std::process::id().to_string())).is_empty());
+    }
+
+    #[test]
+    fn classifies_changes() {
+        let host = Namespaces([1, 2, 3, 4, 5, 6, 7].map(Some));
+        let on_host = process("/usr/bin/bash", [1, 2, 3, 4, 5, 6, 7]);
+        let in_container = process("/usr/bin/bash", [1, 2, 13, 4, 15, 6, 7]);
+        let runc = process("/usr/bin/runc", [1, 2, 3, 4, 5, 6, 7]);
+        let nsenter = process("/usr/bin/nsenter", [1, 2, 3, 4, 5, 6, 7]);
+        let other_net = process("/usr/bin/bash", [1, 2, 3, 14, 5, 6, 7]);
+        assert_eq!(None, change_from(&on_host.namespaces, &on_host, &host));
+        assert_eq!(None, change_from(&in_container.namespaces, &runc, &host));
+        assert_eq!(Some("escape"), change_from(&on_host.namespaces, &in_container, &host));
+        assert_eq!(Some("nsenter"), change_from(&in_container.namespaces, &nsenter, &host));
+        assert_eq!(Some("other"), change_from(&other_net.namespaces, &on_host, &host));
+        assert_eq!(None, change_from(&Namespaces::default(), &in_container, &host));
+    }
+
+    #[test]
+    fn allows_sandboxes() {
+        let host = Namespaces([1, 2, 3, 4, 5, 6, 7].map(Some));
+        let systemd = process("/usr/lib/systemd/systemd", [1, 2, 3, 4, 5, 6, 7]);
+        let private_tmp = process("/usr/sbin/nginx", [1, 12, 13, 14, 5, 6, 17]);
+        let private_pid = process("/usr/sbin/nginx", [1, 2, 3, 4, 15, 6, 7]);
+        assert_eq!(None, change_from(&private_tmp.namespaces, &systemd, &host));
+        assert_eq!(Some("other"), change_from(&private_pid.namespaces, &systemd, &host));
+
+        let chrome = process("/opt/google/chrome/chrome", [1, 2, 3, 4, 5, 6, 7]);
+        let renderer = process("/opt/google/chrome/chrome", [1, 2, 3, 14, 15, 16, 7]);
+        assert_eq!(None, change_from(&renderer.namespaces, &chrome, &host));
+        let snap = process("/usr/lib/snapd/snap-confine", [1, 2, 3, 4, 5, 6, 7]);
+        let confined = process("/snap/firefox/current/usr/lib/firefox/firefox", [1, 2, 13, 4, 5, 6, 7]);
+        assert_eq!(None, change_from(&confined.namespaces, &snap, &host));
+        assert_eq!(Some("other"), change_from(&renderer.namespaces, &snap, &host));
+    }
+}
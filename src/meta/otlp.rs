@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::*;
+use metrics::Key;
+use serde_json::{json, Value};
+use super::aggregator::Aggregator;
+use super::event::Event;
+use super::grpc;
+use super::http;
+use super::info::hostname;
+use super::push::{bytes_field, varint};
+use super::sink::Sink;
+
+/// Maximum number of log records in one request
+const BATCH_SIZE: usize = 512;
+/// Maximum number of log records waiting to be sent
+const QUEUE_SIZE: usize = 8192;
+/// AGGREGATION_TEMPORALITY_CUMULATIVE
+const CUMULATIVE: u8 = 2;
+/// SEVERITY_NUMBER_INFO
+const INFO: u8 = 9;
+/// SEVERITY_NUMBER_WARN
+const WARN: u8 = 13;
+
+/// How requests are sent to the collector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// JSON encoding over HTTP/1.1 to `<endpoint>/v1/<signal>`
+    HttpJson,
+    /// Protobuf encoding over HTTP/2 without TLS
+    Grpc,
+}
+
+impl Protocol {
+    /// Accepts the same names, as `OTEL_EXPORTER_OTLP_PROTOCOL`
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "http/json" => Ok(Protocol::HttpJson),
+            "grpc" => Ok(Protocol::Grpc),
+            other => Err(Error::new(ErrorKind::InvalidInput,
+                format!("OTLP protocol {} is neither http/json nor grpc", other))),
+        }
+    }
+}
+
+/// Type of OTLP data, that is sent to its own path or gRPC service
+struct Signal {
+    path: &'static str,
+    method: &'static str,
+    schema: &'static [Field],
+}
+
+static METRICS: Signal = Signal{
+    path: "v1/metrics",
+    method: "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export",
+    schema: &METRICS_REQUEST,
+};
+
+static LOGS: Signal = Signal{
+    path: "v1/logs",
+    method: "/opentelemetry.proto.collector.logs.v1.LogsService/Export",
+    schema: &LOGS_REQUEST,
+};
+
+/// OpenTelemetry collector, that receives requests in OTLP/JSON form
+#[derive(Debug, Clone)]
+struct Collector {
+    endpoint: String,
+    protocol: Protocol,
+}
+
+impl Collector {
+    fn send(&self, signal: &Signal, request: &Value) -> Result<()> {
+        match self.protocol {
+            Protocol::HttpJson => {
+                let url = format!("{}/{}", self.endpoint.trim_end_matches('/'), signal.path);
+                post(&url, request.to_string().as_bytes()).map(|_| ())
+            }
+            Protocol::Grpc => grpc::call(&self.endpoint, signal.method, &protobuf(request, signal.schema)),
+        }
+    }
+}
+
+/// Pushes metrics to the collector every interval in the background thread
+pub fn export(aggregator: &Aggregator, endpoint: &str, protocol: Protocol, interval: Duration) -> Result<()> {
+    let collector = Collector{endpoint: String::from(endpoint), protocol};
+    let aggregator = aggregator.clone();
+    thread::Builder::new()
+        .name(String::from("prom-cnproc-otlp-metrics"))
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = collector.send(&METRICS, &metrics_request(&aggregator)) {
+                warn!("cannot export metrics: {}", e);
+            }
+        })?;
+    Ok(())
+}
+
+/// Returns `ExportMetricsServiceRequest` in OTLP/JSON encoding
+fn metrics_request(aggregator: &Aggregator) -> Value {
+    let store = aggregator.store();
+    let start = nanos(aggregator.start);
+    let now = nanos(SystemTime::now());
+    let mut metrics = BTreeMap::new();
+    for (key, value) in &store.gauges {
+        data_points(&mut metrics, key.name(), "gauge").push(json!({
+            "attributes": attributes(key),
+            "timeUnixNano": now,
+            "asDouble": value,
+        }));
+    }
+    for (key, value) in &store.counters {
+        data_points(&mut metrics, key.name(), "sum").push(json!({
+            "attributes": attributes(key),
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "asInt": value.to_string(),
+        }));
+    }
+    for (key, histogram) in &store.histograms {
+        data_points(&mut metrics, key.name(), "histogram").push(json!({
+            "attributes": attributes(key),
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "count": histogram.count.to_string(),
+            "sum": histogram.sum,
+            "bucketCounts": histogram.counts.iter().map(u64::to_string).collect::<Vec<_>>(),
+            "explicitBounds": histogram.bounds,
+        }));
+    }
+    json!({"resourceMetrics": [{
+        "resource": resource(),
+        "scopeMetrics": [{"scope": scope(), "metrics": metrics.into_values().collect::<Vec<_>>()}],
+    }]})
+}
+
+/// Returns data points of the metric, that is added on first use
+fn data_points<'a>(metrics: &'a mut BTreeMap<String, Value>, name: &str, kind: &str) -> &'a mut Vec<Value> {
+    let metric = metrics.entry(String::from(name)).or_insert_with(|| {
+        let mut data = json!({"dataPoints": []});
+        if kind != "gauge" {
+            data["aggregationTemporality"] = json!(CUMULATIVE);
+        }
+        if kind == "sum" {
+            data["isMonotonic"] = json!(true);
+        }
+        let mut metric = json!({"name": name});
+        metric[kind] = data;
+        metric
+    });
+    match metric[kind]["dataPoints"].as_array_mut() {
+        Some(points) => points,
+        None => unreachable!("{} is not a {}", name, kind),
+    }
+}
+
+/// Sends events as OpenTelemetry log records in batches
+pub struct Logs {
+    queue: SyncSender<Event>,
+}
+
+impl Logs {
+    /// Starts the background thread, that sends batches to the collector
+    /// every interval or once there's enough of them
+    pub fn new(endpoint: &str, protocol: Protocol, interval: Duration) -> Result<Self> {
+        let collector = Collector{endpoint: String::from(endpoint), protocol};
+        let (queue, events) = sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name(String::from("prom-cnproc-otlp-logs"))
+            .spawn(move || send_logs(&collector, events, interval))?;
+        Ok(Self{queue})
+    }
+}
+
+impl Sink for Logs {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        match self.queue.try_send(event.clone()) {
+            Err(TrySendError::Full(_)) => Err(Error::other("log records queue is full")),
+            Err(TrySendError::Disconnected(_)) => Err(Error::other("log records thread has stopped")),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+fn send_logs(collector: &Collector, events: Receiver<Event>, interval: Duration) {
+    let mut batch = vec![];
+    let mut deadline = Instant::now() + interval;
+    loop {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(event) => batch.push(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if batch.len() < BATCH_SIZE && Instant::now() < deadline {
+            continue;
+        }
+        deadline = Instant::now() + interval;
+        if batch.is_empty() {
+            continue;
+        }
+        let sent = logs_request(&batch).and_then(|request| collector.send(&LOGS, &request));
+        if let Err(e) = sent {
+            warn!("cannot export {} log records: {}", batch.len(), e);
+        }
+        batch.clear();
+    }
+}
+
+/// Returns `ExportLogsServiceRequest` in OTLP/JSON encoding
+fn logs_request(events: &[Event]) -> Result<Value> {
+    let now = nanos(SystemTime::now());
+    let mut records = vec![];
+    for event in events {
+        let fields = match serde_json::to_value(event)? {
+            Value::Object(fields) => fields,
+            _ => continue,
+        };
+        let attributes: Vec<Value> = fields.into_iter()
+            .map(|(key, value)| json!({"key": format!("cnproc.{}", key), "value": any_value(value)}))
+            .collect();
+        records.push(json!({
+            "timeUnixNano": now,
+            "observedTimeUnixNano": now,
+            "severityNumber": if event.kind.severity() < 6 { WARN } else { INFO },
+            "severityText": if event.kind.severity() < 6 { "WARN" } else { "INFO" },
+            "body": {"stringValue": format!("{} {} {}", event.kind.as_str(), event.tree, event.exe)},
+            "attributes": attributes,
+        }));
+    }
+    Ok(json!({"resourceLogs": [{
+        "resource": resource(),
+        "scopeLogs": [{"scope": scope(), "logRecords": records}],
+    }]}))
+}
+
+fn post(url: &str, body: &[u8]) -> Result<u16> {
+    http::request("POST", url, &[("Content-Type", "application/json")], body)
+}
+
+/// Converts JSON value into OTLP `AnyValue`
+fn any_value(value: Value) -> Value {
+    match value {
+        Value::String(s) => json!({"stringValue": s}),
+        Value::Bool(b) => json!({"boolValue": b}),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => json!({"intValue": i.to_string()}),
+            None => json!({"doubleValue": n.as_f64()}),
+        },
+        Value::Array(items) => json!({"arrayValue": {
+            "values": items.into_iter().map(any_value).collect::<Vec<_>>(),
+        }}),
+        other => json!({"stringValue": other.to_string()}),
+    }
+}
+
+fn attributes(key: &Key) -> Vec<Value> {
+    key.labels()
+        .map(|l| json!({"key": l.key(), "value": {"stringValue": l.value()}}))
+        .collect()
+}
+
+fn resource() -> Value {
+    json!({"attributes": [
+        {"key": "service.name", "value": {"stringValue": "prom-cnproc"}},
+        {"key": "host.name", "value": {"stringValue": hostname()}},
+    ]})
+}
+
+fn scope() -> Value {
+    json!({"name": "prom-cnproc", "version": env!("CARGO_PKG_VERSION")})
+}
+
+/// Nanoseconds since epoch as string, like 64-bit integers in OTLP/JSON
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+/// Protobuf type of the field
+#[derive(Clone, Copy)]
+enum Wire {
+    String,
+    Bool,
+    /// int64 or enum
+    Varint,
+    Double,
+    /// fixed64 or sfixed64, that are strings in OTLP/JSON
+    Fixed64,
+    Message(&'static [Field]),
+}
+
+/// JSON name, number and type of the field in OTLP protobuf messages, that are used here
+type Field = (&'static str, u64, Wire);
+
+static METRICS_REQUEST: [Field; 1] = [("resourceMetrics", 1, Wire::Message(&RESOURCE_METRICS))];
+static RESOURCE_METRICS: [Field; 2] = [
+    ("resource", 1, Wire::Message(&RESOURCE)),
+    ("scopeMetrics", 2, Wire::Message(&SCOPE_METRICS)),
+];
+static SCOPE_METRICS: [Field; 2] = [("scope", 1, Wire::Message(&SCOPE)), ("metrics", 2, Wire::Message(&METRIC))];
+static METRIC: [Field; 4] = [
+    ("name", 1, Wire::String),
+    ("gauge", 5, Wire::Message(&GAUGE)),
+    ("sum", 7, Wire::Message(&SUM)),
+    ("histogram", 9, Wire::Message(&HISTOGRAM)),
+];
+static GAUGE: [Field; 1] = [("dataPoints", 1, Wire::Message(&NUMBER_POINT))];
+static SUM: [Field; 3] = [
+    ("dataPoints", 1, Wire::Message(&NUMBER_POINT)),
+    ("aggregationTemporality", 2, Wire::Varint),
+    ("isMonotonic", 3, Wire::Bool),
+];
+static HISTOGRAM: [Field; 2] = [
+    ("dataPoints", 1, Wire::Message(&HISTOGRAM_POINT)),
+    ("aggregationTemporality", 2, Wire::Varint),
+];
+static NUMBER_POINT: [Field; 5] = [
+    ("startTimeUnixNano", 2, Wire::Fixed64),
+    ("timeUnixNano", 3, Wire::Fixed64),
+    ("asDouble", 4, Wire::Double),
+    ("asInt", 6, Wire::Fixed64),
+    ("attributes", 7, Wire::Message(&KEY_VALUE)),
+];
+static HISTOGRAM_POINT: [Field; 7] = [
+    ("startTimeUnixNano", 2, Wire::Fixed64),
+    ("timeUnixNano", 3, Wire::Fixed64),
+    ("count", 4, Wire::Fixed64),
+    ("sum", 5, Wire::Double),
+    ("bucketCounts", 6, Wire::Fixed64),
+    ("explicitBounds", 7, Wire::Double),
+    ("attributes", 9, Wire::Message(&KEY_VALUE)),
+];
+static LOGS_REQUEST: [Field; 1] = [("resourceLogs", 1, Wire::Message(&RESOURCE_LOGS))];
+static RESOURCE_LOGS: [Field; 2] = [
+    ("resource", 1, Wire::Message(&RESOURCE)),
+    ("scopeLogs", 2, Wire::Message(&SCOPE_LOGS)),
+];
+static SCOPE_LOGS: [Field; 2] = [("scope", 1, Wire::Message(&SCOPE)), ("logRecords", 2, Wire::Message(&LOG_RECORD))];
+static LOG_RECORD: [Field; 6] = [
+    ("timeUnixNano", 1, Wire::Fixed64),
+    ("severityNumber", 2, Wire::Varint),
+    ("severityText", 3, Wire::String),
+    ("body", 5, Wire::Message(&ANY_VALUE)),
+    ("attributes", 6, Wire::Message(&KEY_VALUE)),
+    ("observedTimeUnixNano", 11, Wire::Fixed64),
+];
+static RESOURCE: [Field; 1] = [("attributes", 1, Wire::Message(&KEY_VALUE))];
+static SCOPE: [Field; 2] = [("name", 1, Wire::String), ("version", 2, Wire::String)];
+static KEY_VALUE: [Field; 2] = [("key", 1, Wire::String), ("value", 2, Wire::Message(&ANY_VALUE))];
+static ANY_VALUE: [Field; 5] = [
+    ("stringValue", 1, Wire::String),
+    ("boolValue", 2, Wire::Bool),
+    ("intValue", 3, Wire::Varint),
+    ("doubleValue", 4, Wire::Double),
+    ("arrayValue", 5, Wire::Message(&ARRAY_VALUE)),
+];
+static ARRAY_VALUE: [Field; 1] = [("values", 1, Wire::Message(&ANY_VALUE))];
+
+/// Encodes OTLP/JSON request as protobuf, so that both protocols send the same data.
+/// Repeated numbers are packed and fields, that aren't in the schema, are skipped.
+fn protobuf(value: &Value, schema: &[Field]) -> Vec<u8> {
+    let mut out = vec![];
+    for (name, field, wire) in schema {
+        match (&value[*name], wire) {
+            (Value::Null, _) => {}
+            (Value::Array(items), Wire::Message(_)) => {
+                for item in items {
+                    field_value(&mut out, *field, *wire, item);
+                }
+            }
+            (Value::Array(items), _) => {
+                let mut packed = vec![];
+                for item in items {
+                    scalar(&mut packed, *wire, item);
+                }
+                bytes_field(&mut out, *field, &packed);
+            }
+            (item, _) => field_value(&mut out, *field, *wire, item),
+        }
+    }
+    out
+}
+
+fn field_value(out: &mut Vec<u8>, field: u64, wire: Wire, value: &Value) {
+    match wire {
+        Wire::String => bytes_field(out, field, value.as_str().unwrap_or_default().as_bytes()),
+        Wire::Message(schema) => bytes_field(out, field, &protobuf(value, schema)),
+        Wire::Bool | Wire::Varint => {
+            varint(out, field << 3);
+            scalar(out, wire, value);
+        }
+        Wire::Double | Wire::Fixed64 => {
+            varint(out, field << 3 | 1);
+            scalar(out, wire, value);
+        }
+    }
+}
+
+/// Appends number without the tag, like in packed fields
+fn scalar(out: &mut Vec<u8>, wire: Wire, value: &Value) {
+    // OTLP/JSON has 64-bit integers as strings
+    let integer = match value {
+        Value::String(s) => s.parse().unwrap_or_default(),
+        other => other.as_i64().unwrap_or_default(),
+    };
+    match wire {
+        Wire::Bool => out.push(value.as_bool().unwrap_or_default() as u8),
+        Wire::Varint => varint(out, integer as u64),
+        Wire::Double => out.extend_from_slice(&value.as_f64().unwrap_or_default().to_le_bytes()),
+        Wire::Fixed64 => out.extend_from_slice(&integer.to_le_bytes()),
+        Wire::String | Wire::Message(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::event::Kind;
+    use super::super::grpc::tests::server as grpc_server;
+    use super::super::http::tests::server;
+    use super::super::info::Process;
+    use metrics::{GaugeValue, Label, Recorder};
+
+    #[test]
+    fn exports_metrics() {
+        let aggregator = Aggregator::new();
+        let tree = Key::from_parts("process", vec![Label::new("tree", "/sshd/bash")]);
+        aggregator.update_gauge(&tree, GaugeValue::Absolute(1.));
+        aggregator.update_gauge(&tree, GaugeValue::Increment(2.));
+        let seconds = Key::from_parts("process_seconds", vec![Label::new("tree", "/sshd/bash")]);
+        aggregator.record_histogram(&seconds, 0.02);
+        aggregator.record_histogram(&seconds, 7200.);
+        aggregator.increment_counter(&Key::from_name("process_sink_errors_total"), 3);
+
+        let (url, rx) = server(200);
+        export(&aggregator, &url, Protocol::HttpJson, Duration::from_millis(10)).unwrap();
+        let (head, body) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(head.starts_with("POST /v1/metrics HTTP/1.1"));
+        let request: Value = serde_json::from_str(&body).unwrap();
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!("process", metrics[0]["name"]);
+        let gauge = &metrics[0]["gauge"]["dataPoints"][0];
+        assert_eq!(3., gauge["asDouble"]);
+        assert_eq!("tree", gauge["attributes"][0]["key"]);
+        assert_eq!("process_seconds", metrics[1]["name"]);
+        let histogram = &metrics[1]["histogram"]["dataPoints"][0];
+        assert_eq!("2", histogram["count"]);
+        assert_eq!("1", histogram["bucketCounts"][1]);
+        assert_eq!("1", histogram["bucketCounts"][12]);
+        assert_eq!("process_sink_errors_total", metrics[2]["name"]);
+        assert_eq!("3", metrics[2]["sum"]["dataPoints"][0]["asInt"]);
+    }
+
+    #[test]
+    fn exports_logs() {
+        let mut prc = Process::from(3, 2, "/usr/bin/top", vec![String::from("top")]);
+        prc.tree = String::from("/sshd/bash/top");
+        let (url, rx) = server(200);
+        let mut logs = Logs::new(&url, Protocol::HttpJson, Duration::from_millis(50)).unwrap();
+        logs.emit(&Event::new(Kind::Exec, &prc)).unwrap();
+        logs.emit(&Event::new(Kind::Exit, &prc)).unwrap();
+        let (head, body) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(head.starts_with("POST /v1/logs HTTP/1.1"));
+        let request: Value = serde_json::from_str(&body).unwrap();
+        let records = &request["resourceLogs"][0]["scopeLogs"][0]["logRecords"];
+        assert_eq!(2, records.as_array().unwrap().len());
+        assert_eq!("exec /sshd/bash/top /usr/bin/top", records[0]["body"]["stringValue"]);
+        let attributes = records[0]["attributes"].as_array().unwrap();
+        let pid = attributes.iter().find(|a| a["key"] == "cnproc.pid").unwrap();
+        assert_eq!("3", pid["value"]["intValue"]);
+    }
+
+    #[test]
+    fn encodes_protobuf() {
+        let attribute = json!({"key": "a", "value": {"intValue": "3"}});
+        assert_eq!(vec![0x0a, 1, b'a', 0x12, 2, 0x18, 3], protobuf(&attribute, &KEY_VALUE));
+        let point = json!({"count": "2", "bucketCounts": ["1", "1"], "attributes": [attribute, attribute]});
+        let encoded = protobuf(&point, &HISTOGRAM_POINT);
+        assert_eq!(&[0x21, 2, 0, 0, 0, 0, 0, 0, 0, 0x32, 16, 1][..], &encoded[..12]);
+        // two separate attributes after packed buckets
+        assert_eq!(9 + 18 + 2 * 9, encoded.len());
+        assert!(Protocol::parse("http/protobuf").is_err());
+    }
+
+    #[test]
+    fn exports_over_grpc() {
+        let mut prc = Process::from(3, 2, "/usr/bin/top", vec![String::from("top")]);
+        prc.tree = String::from("/sshd/bash/top");
+        let (url, rx) = grpc_server(0);
+        let mut logs = Logs::new(&url, Protocol::Grpc, Duration::from_millis(50)).unwrap();
+        logs.emit(&Event::new(Kind::Exec, &prc)).unwrap();
+        let (method, message) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(LOGS.method, method);
+        // resourceLogs
+        assert_eq!(0x0a, message[0]);
+        let body = b"exec /sshd/bash/top /usr/bin/top";
+        assert!(message.windows(body.len()).any(|w| w == body));
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::sync::{RwLock, RwLockReadGuard};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, Result};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use lazy_static::lazy_static;
+use log::*;
+use metrics::gauge;
+use super::known;
+
+/// Directories, where binaries of the base system are installed
+const BIN_DIRS: [&str; 4] = ["/bin/", "/sbin/", "/usr/bin/", "/usr/sbin/"];
+
+/// Directories, that never have anything executable in them
+const SKIP_DIRS: [&str; 8] = [
+    "/usr/include/",
+    "/usr/share/doc/",
+    "/usr/share/i18n/",
+    "/usr/share/icons/",
+    "/usr/share/info/",
+    "/usr/share/locale/",
+    "/usr/share/man/",
+    "/usr/share/zoneinfo/",
+];
+
+/// Top-level directories, that are symlinks on merged /usr systems
+const MERGED_DIRS: [&str; 6] = ["/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32"];
+
+/// How often the package database is checked for changes
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reads file paths and their owning packages from one of the package databases
+type Loader = fn() -> Result<Vec<(String, String)>>;
+
+lazy_static! {
+    static ref DB: PackageDb = PackageDb::load();
+}
+
+/// Package database, that was used to build the set of base binaries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Dpkg,
+    Rpm,
+    Apk,
+    Compiled,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Dpkg => "dpkg",
+            Source::Rpm => "rpm",
+            Source::Apk => "apk",
+            Source::Compiled => "compiled",
+        }
+    }
+
+    /// Files, that are modified on every install or removal of a package
+    fn stamps(&self) -> &'static [&'static str] {
+        match self {
+            Source::Dpkg => &["/var/lib/dpkg/status"],
+            Source::Rpm => &["/var/lib/rpm/rpmdb.sqlite", "/var/lib/rpm/Packages"],
+            Source::Apk => &["/lib/apk/db/installed"],
+            Source::Compiled => &[],
+        }
+    }
+
+    /// Latest modification time of the database
+    fn modified(&self) -> Option<SystemTime> {
+        self.stamps().iter()
+            .filter_map(|stamp| fs::metadata(stamp).and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+/// Owners of every packaged file, that is built at once and
+/// replaced as a whole, when the database changes
+struct Index {
+    owners: HashMap<String, u32>,
+    packages: Vec<String>,
+}
+
+impl Index {
+    fn from(files: Vec<(String, String)>) -> Self {
+        let merged = merged_dirs();
+        let mut owners = HashMap::new();
+        let mut packages: Vec<String> = vec![];
+        for (file, package) in files {
+            if SKIP_DIRS.iter().any(|dir| file.starts_with(dir)) {
+                continue;
+            }
+            if packages.last() != Some(&package) {
+                packages.push(package);
+            }
+            let id = (packages.len() - 1) as u32;
+            if BIN_DIRS.iter().any(|dir| file.starts_with(dir)) {
+                // executed binaries are canonicalized, so /bin/sh has
+                // to be resolved to /usr/bin/dash
+                if let Ok(canonical) = fs::canonicalize(&file) {
+                    if let Some(canonical) = canonical.to_str() {
+                        owners.entry(String::from(canonical)).or_insert(id);
+                    }
+                }
+            } else if let Some(resolved) = resolve(&merged, &file) {
+                // it's way too slow to canonicalize every packaged file
+                owners.entry(resolved).or_insert(id);
+            }
+            owners.insert(file, id);
+        }
+        Self{owners, packages}
+    }
+}
+
+/// Files, that are installed by the package manager of this host
+pub struct PackageDb {
+    pub source: Source,
+    /// Reads the database again, once it's changed
+    loader: Option<Loader>,
+    index: RwLock<Index>,
+    /// Modification time of the database, that the index was built from
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl PackageDb {
+    /// Reads the first available package database and falls back
+    /// to the list, that was generated at compile time
+    pub fn load() -> Self {
+        let loaders: [(Source, Loader); 3] = [
+            (Source::Dpkg, || dpkg(Path::new("/var/lib/dpkg/info"))),
+            (Source::Rpm, rpm),
+            (Source::Apk, || apk(Path::new("/lib/apk/db/installed"))),
+        ];
+        for (source, loader) in loaders.iter() {
+            let modified = source.modified();
+            match loader() {
+                Ok(files) if !files.is_empty() => {
+                    let db = Self::from(*source, files, Some(*loader));
+                    *db.modified.write().unwrap() = modified;
+                    return db;
+                }
+                Ok(_) => debug!("{} database has no files", source.as_str()),
+                Err(e) => debug!("{} database is not available: {}", source.as_str(), e),
+            }
+        }
+        // there's no way to know owning packages of compiled list
+        let unknown = String::from("unknown");
+        Self::from(Source::Compiled, known::compiled()
+            .map(|file| (String::from(file), unknown.clone()))
+            .collect(), None)
+    }
+
+    fn from(source: Source, files: Vec<(String, String)>, loader: Option<Loader>) -> Self {
+        Self{source, loader, index: RwLock::new(Index::from(files)), modified: RwLock::new(None)}
+    }
+
+    fn index(&self) -> RwLockReadGuard<'_, Index> {
+        self.index.read().unwrap()
+    }
+
+    /// Number of packaged binaries in the base system
+    pub fn len(&self) -> usize {
+        self.index().owners.keys()
+            .filter(|file| BIN_DIRS.iter().any(|dir| file.starts_with(dir)))
+            .count()
+    }
+
+    /// Number of packaged files, which owners are kept in memory
+    pub fn indexed(&self) -> usize {
+        self.index().owners.len()
+    }
+
+    /// Returns name of the package, that installed the file
+    pub fn owner(&self, file: &str) -> Option<String> {
+        let index = self.index();
+        index.owners.get(file).map(|id| index.packages[*id as usize].clone())
+    }
+
+    /// Returns owner of the file, `unpackaged` if there's none,
+    /// or `unknown` if only the compiled list is available
+    pub fn package(&self, file: &str) -> String {
+        match self.owner(file) {
+            Some(package) => package,
+            // compiled list doesn't know about the rest of packages
+            None if self.source == Source::Compiled => String::from("unknown"),
+            None => String::from("unpackaged"),
+        }
+    }
+
+    /// Checks if binary belongs to the base system
+    pub fn is_base(&self, binary: &str) -> bool {
+        BIN_DIRS.iter().any(|dir| binary.starts_with(dir))
+            && self.index().owners.contains_key(binary)
+    }
+
+    /// Builds the index again, if the database has changed since the last time.
+    /// Lookups use the previous index, while the new one is being built.
+    fn refresh(&self) -> Result<bool> {
+        let loader = match self.loader {
+            Some(loader) => loader,
+            None => return Ok(false),
+        };
+        let modified = self.source.modified();
+        if modified.is_none() || modified == *self.modified.read().unwrap() {
+            return Ok(false);
+        }
+        let index = Index::from(loader()?);
+        *self.index.write().unwrap() = index;
+        *self.modified.write().unwrap() = modified;
+        Ok(true)
+    }
+
+    /// Reports the size of the index
+    pub fn gauges(&self) {
+        gauge!("process_base_entries", self.len() as f64, "source" => self.source.as_str());
+        gauge!("process_package_index_entries", self.indexed() as f64, "source" => self.source.as_str());
+    }
+}
+
+/// Checks the global package database for changes in the background thread
+pub fn watch() -> Result<()> {
+    if DB.loader.is_none() {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name(String::from("prom-cnproc-packages"))
+        .spawn(|| loop {
+            thread::sleep(REFRESH_INTERVAL);
+            match DB.refresh() {
+                Ok(true) => {
+                    info!("reloaded {} packaged files from {}", DB.indexed(), DB.source.as_str());
+                    DB.gauges();
+                }
+                Ok(false) => {}
+                Err(e) => warn!("cannot reload {} database: {}", DB.source.as_str(), e),
+            }
+        })?;
+    Ok(())
+}
+
+/// Returns top-level directories, that are symlinks to /usr
+fn merged_dirs() -> Vec<(&'static str, String)> {
+    let mut merged = vec![];
+    for dir in MERGED_DIRS.iter() {
+        if let Ok(target) = fs::canonicalize(dir) {
+            if let Some(target) = target.to_str() {
+                if target != *dir {
+                    merged.push((*dir, String::from(target)));
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// Rewrites /lib/foo into /usr/lib/foo, if /lib is a symlink to /usr/lib
+fn resolve(merged: &[(&str, String)], file: &str) -> Option<String> {
+    for (dir, target) in merged {
+        if let Some(rest) = file.strip_prefix(dir) {
+            if rest.starts_with('/') {
+                return Some(format!("{}{}", target, rest));
+            }
+        }
+    }
+    None
+}
+
+/// Returns globally loaded package database
+pub fn db() -> &'static PackageDb {
+    &DB
+}
+
+/// Checks if binary belongs to the base system
+pub fn is_base(binary: &str) -> bool {
+    DB.is_base(binary)
+}
+
+/// Reads files from Debian package lists
+fn dpkg(info: &Path) -> Result<Vec<(String, String)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(info)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("list") {
+            continue;
+        }
+        // multiarch packages have lists like libc6:amd64.list
+        let package = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem.split(':').next().unwrap_or(stem).to_string(),
+            None => continue,
+        };
+        // one broken list shouldn't throw away the rest of the database
+        let list = match File::open(&path) {
+            Ok(list) => BufReader::new(list),
+            Err(e) => {
+                warn!("skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        for line in list.lines() {
+            match line {
+                Ok(line) => files.push((line, package.clone())),
+                Err(e) => {
+                    warn!("skipping rest of {}: {}", path.display(), e);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Asks RPM for files of all installed packages, because
+/// the database itself is not trivial to parse
+fn rpm() -> Result<Vec<(String, String)>> {
+    let out = Command::new("rpm")
+        .args(["-qa", "--qf", "[%{FILENAMES}\t%{NAME}\n]"])
+        .output()?;
+    if !out.status.success() {
+        return Err(Error::other(format!("rpm exited with {}", out.status)));
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    Ok(stdout.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(file, package)| (String::from(file), String::from(package)))
+        .collect())
+}
+
+/// Reads files from Alpine installed packages database
+fn apk(installed: &Path) -> Result<Vec<(String, String)>> {
+    let mut files = vec![];
+    let mut package = String::new();
+    let mut dir = String::new();
+    let db = BufReader::new(File::open(installed)?);
+    for line in db.lines() {
+        let line = line?;
+        if let Some(name) = line.strip_prefix("P:") {
+            package = String::from(name);
+        } else if let Some(folder) = line.strip_prefix("F:") {
+            dir = String::from(folder);
+        } else if let Some(file) = line.strip_prefix("R:") {
+            files.push((format!("/{}/{}", dir, file), package.clone()));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use super::super::testing::TempDir;
+
+    #[test]
+    fn dpkg_lists() {
+        let dir = TempDir::new("dpkg");
+        let mut list = File::create(dir.join("coreutils.list")).unwrap();
+        writeln!(list, "/.\n/usr/bin\n/usr/bin/dd\n/usr/share/doc/coreutils").unwrap();
+        let mut list = File::create(dir.join("libc-bin:amd64.list")).unwrap();
+        writeln!(list, "/usr/sbin/ldconfig").unwrap();
+        let mut md5 = File::create(dir.join("coreutils.md5sums")).unwrap();
+        writeln!(md5, "0000  usr/bin/dd").unwrap();
+        // directory with .list extension can't be read as a file
+        fs::create_dir_all(dir.join("broken.list")).unwrap();
+
+        let files = dpkg(dir.path()).unwrap();
+        assert_eq!(5, files.len());
+
+        let db = PackageDb::from(Source::Dpkg, files, None);
+        assert!(db.is_base("/usr/bin/dd"));
+        assert!(!db.is_base("/usr/bin"));
+        assert_eq!(Some(String::from("coreutils")), db.owner("/usr/bin/dd"));
+        assert_eq!(Some(String::from("libc-bin")), db.owner("/usr/sbin/ldconfig"));
+        assert_eq!(None, db.owner("/usr/share/doc/coreutils"));
+        assert_eq!(None, db.owner("/opt/app/bin/server"));
+    }
+
+    #[test]
+    fn apk_installed() {
+        let dir = TempDir::new("apk");
+        let installed = dir.join("installed");
+        let mut db = File::create(&installed).unwrap();
+        writeln!(db, "C:Q1\nP:busybox\nV:1.33.1-r3\nF:bin\nR:busybox\nF:etc\nR:securetty").unwrap();
+
+        let files = apk(&installed).unwrap();
+        assert_eq!(vec![
+            (String::from("/bin/busybox"), String::from("busybox")),
+            (String::from("/etc/securetty"), String::from("busybox")),
+        ], files);
+    }
+
+    #[test]
+    fn merged_usr() {
+        let merged = vec![("/lib", String::from("/usr/lib"))];
+        assert_eq!(Some(String::from("/usr/lib/systemd/systemd")),
+            resolve(&merged, "/lib/systemd/systemd"));
+        assert_eq!(None, resolve(&merged, "/lib64/ld-linux-x86-64.so.2"));
+        assert_eq!(None, resolve(&merged, "/opt/lib/app"));
+    }
+
+    #[test]
+    fn compiled_fallback() {
+        let files = known::compiled()
+            .map(|file| (String::from(file), String::from("unknown")))
+            .collect();
+        let db = PackageDb::from(Source::Compiled, files, None);
+        assert!(db.is_base("/usr/bin/apt"));
+    }
+
+    #[test]
+    fn indexes_scripts() {
+        let files = vec![
+            (String::from("/usr/bin/unattended-upgrade"), String::from("unattended-upgrades")),
+            (String::from("/usr/share/unattended-upgrades/shutdown"), String::from("unattended-upgrades")),
+            (String::from("/usr/share/perl5/Debconf/Log.pm"), String::from("debconf")),
+        ];
+        let db = PackageDb::from(Source::Dpkg, files, None);
+        assert_eq!(3, db.indexed());
+        assert_eq!("unattended-upgrades", db.package("/usr/share/unattended-upgrades/shutdown"));
+        assert_eq!("debconf", db.package("/usr/share/perl5/Debconf/Log.pm"));
+        assert_eq!("unpackaged", db.package("/opt/app/server.py"));
+        // without the loader there's nothing to refresh
+        assert!(!db.refresh().unwrap());
+    }
+
+    #[test]
+    fn refreshes_on_change() {
+        let loader: Loader = || Ok(vec![(String::from("/opt/app/bin/app"), String::from("app"))]);
+        let db = PackageDb::from(Source::Dpkg, vec![], Some(loader));
+        assert_eq!(None, db.owner("/opt/app/bin/app"));
+        // modification time of the real database, if there's one, is newer
+        match db.refresh() {
+            Ok(true) => assert_eq!(Some(String::from("app")), db.owner("/opt/app/bin/app")),
+            Ok(false) => assert!(Source::Dpkg.modified().is_none()),
+            Err(e) => panic!("{}", e),
+        }
+        assert!(!db.refresh().unwrap());
+    }
+}
@@ -0,0 +1,98 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Privileges, that the process got from its binary and has after exec
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Privileges {
+    pub setuid: bool,
+    pub setgid: bool,
+    /// Binary has `security.capability` extended attribute
+    pub file_caps: bool,
+    /// Effective capabilities mask from `/proc/<pid>/status`
+    pub effective: Option<u64>,
+}
+
+impl Privileges {
+    /// Looks at the executed binary through procfs, so that deleted ones are seen as well
+    pub fn read(pid: i32) -> Self {
+        let exe = format!("/proc/{}/exe", pid);
+        let mut privileges = of_file(Path::new(&exe));
+        privileges.effective = fs::read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| effective(&status));
+        privileges
+    }
+
+    /// Names of privileges, that the binary grants, for metric labels
+    pub fn kinds(&self) -> Vec<&'static str> {
+        let mut kinds = vec![];
+        if self.setuid {
+            kinds.push("setuid");
+        }
+        if self.setgid {
+            kinds.push("setgid");
+        }
+        if self.file_caps {
+            kinds.push("capabilities");
+        }
+        kinds
+    }
+}
+
+fn of_file(path: &Path) -> Privileges {
+    let mode = fs::metadata(path).map(|m| m.permissions().mode()).unwrap_or(0);
+    Privileges{
+        setuid: mode & libc::S_ISUID != 0,
+        setgid: mode & libc::S_ISGID != 0,
+        file_caps: has_file_caps(path),
+        effective: None,
+    }
+}
+
+fn has_file_caps(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let name = b"security.capability\0";
+    // size of the attribute is enough to know it's there
+    let len = unsafe {
+        libc::getxattr(path.as_ptr(), name.as_ptr() as _, std::ptr::null_mut(), 0)
+    };
+    len > 0
+}
+
+/// Parses `CapEff: 000001ffffffffff` line
+fn effective(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("CapEff:"))?;
+    u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::TempDir;
+
+    #[test]
+    fn parses_status() {
+        let status = "Name:\tping\nCapInh:\t0000000000000000\nCapEff:\t0000000000002000\n";
+        assert_eq!(Some(0x2000), effective(status));
+        assert_eq!(None, effective("Name:\tping\n"));
+        assert!(Privileges::read(std::process::id() as i32).effective.is_some());
+    }
+
+    #[test]
+    fn setuid_binaries() {
+        let dir = TempDir::new("privileges");
+        let path = dir.join("setuid.sh");
+        fs::write(&path, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(of_file(&path).kinds().is_empty());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o6755)).unwrap();
+        let privileges = of_file(&path);
+        assert_eq!(vec!["setuid", "setgid"], privileges.kinds());
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::*;
+use metrics::Key;
+use super::aggregator::{Aggregator, Store};
+use super::http;
+use super::info::hostname;
+
+/// Longest delay between retries of failed pushes
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Where metrics are pushed to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// Replaces metrics of `job="prom-cnproc"` and `instance="<hostname>"` group
+    Pushgateway(String),
+    /// Sends samples with Prometheus remote-write protocol
+    RemoteWrite(String),
+}
+
+impl Target {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::Pushgateway(_) => "pushgateway",
+            Target::RemoteWrite(_) => "remote-write",
+        }
+    }
+
+    /// Number of failed pushes worth keeping. Pushgateway keeps only the last
+    /// push of the group, so replaying older ones would only move it back in time.
+    pub fn spool_max(&self, max: usize) -> usize {
+        match self {
+            Target::Pushgateway(_) => max.min(1),
+            Target::RemoteWrite(_) => max,
+        }
+    }
+
+    fn encode(&self, store: &Store) -> Vec<u8> {
+        match self {
+            Target::Pushgateway(_) => text(store).into_bytes(),
+            Target::RemoteWrite(_) => snappy(&write_request(store, millis(SystemTime::now()))),
+        }
+    }
+
+    fn send(&self, body: &[u8]) -> Result<u16> {
+        match self {
+            Target::Pushgateway(url) => {
+                let url = format!("{}/metrics/job/prom-cnproc/instance/{}", url.trim_end_matches('/'), hostname());
+                http::request("PUT", &url, &[("Content-Type", "text/plain; version=0.0.4")], body)
+            }
+            Target::RemoteWrite(url) => http::request("POST", url, &[
+                ("Content-Type", "application/x-protobuf"),
+                ("Content-Encoding", "snappy"),
+                ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+            ], body),
+        }
+    }
+}
+
+/// Pushes metrics to the target every interval in the background thread.
+/// Failed pushes are kept in the spool and retried with exponential backoff.
+pub fn start(target: Target, aggregator: &Aggregator, interval: Duration, mut spool: Spool) -> Result<()> {
+    let aggregator = aggregator.clone();
+    thread::Builder::new()
+        .name(format!("prom-cnproc-{}", target.name()))
+        .spawn(move || {
+            let mut failures = 0;
+            let mut retry = Instant::now();
+            loop {
+                thread::sleep(interval);
+                let body = target.encode(&aggregator.store());
+                spool.push(body);
+                if Instant::now() < retry {
+                    continue;
+                }
+                match spool.flush(|body| target.send(body)) {
+                    Ok(()) => failures = 0,
+                    Err(e) => {
+                        failures += 1;
+                        let delay = backoff(interval, failures);
+                        warn!("{} failed, {} pushes are pending, retrying in {:?}: {}",
+                            target.name(), spool.len(), delay, e);
+                        retry = Instant::now() + delay;
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.min(16));
+    interval.saturating_mul(factor).min(MAX_BACKOFF.max(interval))
+}
+
+/// Queue of pushes, that are not yet sent. Pushes are kept in the directory,
+/// if it's configured, so that they survive restarts. Oldest are dropped
+/// once there's more than the maximum.
+pub struct Spool {
+    dir: Option<PathBuf>,
+    memory: VecDeque<Vec<u8>>,
+    max: usize,
+}
+
+impl Spool {
+    pub fn new(dir: Option<PathBuf>, max: usize) -> Result<Self> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self{dir, memory: VecDeque::new(), max})
+    }
+
+    /// Files in the directory, oldest first
+    fn files(&self) -> Vec<PathBuf> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return vec![],
+        };
+        let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries.flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "push"))
+                .collect(),
+            Err(_) => vec![],
+        };
+        files.sort();
+        files
+    }
+
+    pub fn len(&self) -> usize {
+        match self.dir {
+            Some(_) => self.files().len(),
+            None => self.memory.len(),
+        }
+    }
+
+    pub fn push(&mut self, body: Vec<u8>) {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => {
+                self.memory.push_back(body);
+                while self.memory.len() > self.max {
+                    self.memory.pop_front();
+                }
+                return;
+            }
+        };
+        // nanoseconds keep files sorted by the time of push
+        let path = dir.join(format!("{:020}.push", SystemTime::now()
+            .duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()));
+        if let Err(e) = fs::write(&path, &body) {
+            warn!("cannot write {}: {}", path.display(), e);
+            return;
+        }
+        let files = self.files();
+        for old in files.iter().take(files.len().saturating_sub(self.max)) {
+            let _ = fs::remove_file(old);
+        }
+    }
+
+    /// Sends pending pushes, oldest first, and stops on the first failure
+    pub fn flush(&mut self, mut send: impl FnMut(&[u8]) -> Result<u16>) -> Result<()> {
+        if self.dir.is_none() {
+            while let Some(body) = self.memory.front() {
+                send(body)?;
+                self.memory.pop_front();
+            }
+            return Ok(());
+        }
+        for file in self.files() {
+            let body = fs::read(&file)?;
+            send(&body)?;
+            fs::remove_file(&file)?;
+        }
+        Ok(())
+    }
+}
+
+/// One metric sample with sorted labels
+type Sample = (String, Vec<(String, String)>, f64);
+
+/// Returns samples grouped by metric name along with the metric type,
+/// where histograms are expanded into buckets, sum and count
+fn families(store: &Store) -> BTreeMap<String, (&'static str, Vec<Sample>)> {
+    let mut families: BTreeMap<String, (&'static str, Vec<Sample>)> = BTreeMap::new();
+    let mut add = |kind, key: &Key, suffix: &str, extra: Option<(&str, String)>, value: f64| {
+        let mut labels: Vec<(String, String)> = key.labels()
+            .map(|l| (String::from(l.key()), String::from(l.value())))
+            .collect();
+        if let Some((name, value)) = extra {
+            labels.push((String::from(name), value));
+        }
+        labels.sort();
+        let family = families.entry(String::from(key.name())).or_insert((kind, vec![]));
+        family.1.push((format!("{}{}", key.name(), suffix), labels, value));
+    };
+    for (key, value) in &store.gauges {
+        add("gauge", key, "", None, *value);
+    }
+    for (key, value) in &store.counters {
+        add("counter", key, "", None, *value as f64);
+    }
+    for (key, histogram) in &store.histograms {
+        let mut cumulative = 0;
+        for (i, count) in histogram.counts.iter().enumerate() {
+            cumulative += count;
+            let le = histogram.bounds.get(i).map_or(String::from("+Inf"), |b| b.to_string());
+            add("histogram", key, "_bucket", Some(("le", le)), cumulative as f64);
+        }
+        add("histogram", key, "_sum", None, histogram.sum);
+        add("histogram", key, "_count", None, histogram.count as f64);
+    }
+    for (_, samples) in families.values_mut() {
+        samples.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    }
+    families
+}
+
+/// Renders metrics in Prometheus text format
+fn text(store: &Store) -> String {
+    let mut out = String::new();
+    for (name, (kind, samples)) in families(store) {
+        out.push_str(&format!("# TYPE {} {}\n", name, kind));
+        for (name, labels, value) in samples {
+            let labels: Vec<String> = labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+                .collect();
+            match labels.is_empty() {
+                true => out.push_str(&format!("{} {}\n", name, value)),
+                false => out.push_str(&format!("{}{{{}}} {}\n", name, labels.join(","), value)),
+            }
+        }
+    }
+    out
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+pub fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends length-delimited protobuf field
+pub fn bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Encodes `prometheus.WriteRequest` protobuf message
+fn write_request(store: &Store, timestamp: i64) -> Vec<u8> {
+    let mut request = vec![];
+    for (_, samples) in families(store).values() {
+        for (name, labels, value) in samples {
+            let mut series = vec![];
+            let name = (String::from("__name__"), name.clone());
+            for (k, v) in std::iter::once(&name).chain(labels.iter()) {
+                let mut label = vec![];
+                bytes_field(&mut label, 1, k.as_bytes());
+                bytes_field(&mut label, 2, v.as_bytes());
+                bytes_field(&mut series, 1, &label);
+            }
+            let mut sample = vec![];
+            varint(&mut sample, 1 << 3 | 1);
+            sample.extend_from_slice(&value.to_le_bytes());
+            varint(&mut sample, 2 << 3);
+            varint(&mut sample, timestamp as u64);
+            bytes_field(&mut series, 2, &sample);
+            bytes_field(&mut request, 1, &series);
+        }
+    }
+    request
+}
+
+/// Encodes data in snappy block format. Only literals are emitted, which
+/// every decoder accepts, so that there's no need for the compression library.
+fn snappy(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 65536 * 3 + 8);
+    varint(&mut out, data.len() as u64);
+    for chunk in data.chunks(65536) {
+        let n = chunk.len() - 1;
+        if n < 60 {
+            out.push((n as u8) << 2);
+        } else if n < 256 {
+            out.push(60 << 2);
+            out.push(n as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::http::tests::server;
+    use super::super::testing::TempDir;
+    use metrics::{GaugeValue, Label, Recorder};
+
+    fn aggregator() -> Aggregator {
+        let aggregator = Aggregator::new();
+        let labels = vec![Label::new("tree", "/sshd/bash"), Label::new("state", "RUNNING")];
+        aggregator.update_gauge(&Key::from_parts("process", labels), GaugeValue::Absolute(1.));
+        let seconds = Key::from_parts("process_seconds", vec![Label::new("tree", "/sshd/bash")]);
+        aggregator.record_histogram(&seconds, 0.02);
+        aggregator
+    }
+
+    #[test]
+    fn text_format() {
+        let text = text(&aggregator().store());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!("# TYPE process gauge", lines[0]);
+        assert_eq!(r#"process{state="RUNNING",tree="/sshd/bash"} 1"#, lines[1]);
+        assert_eq!("# TYPE process_seconds histogram", lines[2]);
+        assert!(lines.contains(&r#"process_seconds_bucket{le="0.01",tree="/sshd/bash"} 0"#));
+        assert!(lines.contains(&r#"process_seconds_bucket{le="0.05",tree="/sshd/bash"} 1"#));
+        assert!(lines.contains(&r#"process_seconds_bucket{le="+Inf",tree="/sshd/bash"} 1"#));
+        assert!(lines.contains(&r#"process_seconds_count{tree="/sshd/bash"} 1"#));
+    }
+
+    #[test]
+    fn snappy_literals() {
+        assert_eq!(vec![3, 2 << 2, b'a', b'b', b'c'], snappy(b"abc"));
+        let long = vec![7u8; 70000];
+        let encoded = snappy(&long);
+        // varint of 70000, then 64k and 4464 byte literals
+        assert_eq!(&[0xf0, 0xa2, 0x04, 61 << 2, 0xff, 0xff], &encoded[..6]);
+        assert_eq!(3 + 3 + 65536 + 3 + 4464, encoded.len());
+    }
+
+    #[test]
+    fn remote_write() {
+        let request = write_request(&aggregator().store(), 1_622_550_896_789);
+        // first timeseries is the gauge with __name__, state and tree labels
+        let mut label = vec![];
+        bytes_field(&mut label, 1, b"__name__");
+        bytes_field(&mut label, 2, b"process");
+        let mut prefix = vec![];
+        bytes_field(&mut prefix, 1, &label);
+        assert_eq!(0x0a, request[0]);
+        assert!(request.windows(prefix.len()).any(|w| w == &prefix[..]));
+        assert!(request.windows(8).any(|w| w == 1f64.to_le_bytes()));
+
+        let (url, rx) = server(204);
+        let target = Target::RemoteWrite(format!("{}/api/v1/write", url));
+        target.send(&snappy(&request)).unwrap();
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("POST /api/v1/write HTTP/1.1"));
+        assert!(head.contains("Content-Encoding: snappy"));
+        assert!(!body.is_empty());
+    }
+
+    #[test]
+    fn spools_failures() {
+        let dir = TempDir::new("spool");
+        let mut spool = Spool::new(Some(dir.join("spool")), 2).unwrap();
+        for body in ["first", "second", "third"].iter() {
+            spool.push(body.as_bytes().to_vec());
+        }
+        let failed = spool.flush(|_| Err(std::io::Error::other("down")));
+        let pending = spool.len();
+        let mut sent = vec![];
+        spool.flush(|body| {
+            sent.push(String::from_utf8(body.to_vec()).unwrap());
+            Ok(200)
+        }).unwrap();
+        let left = spool.len();
+        assert!(failed.is_err());
+        assert_eq!(2, pending);
+        assert_eq!(vec!["second", "third"], sent);
+        assert_eq!(0, left);
+        assert_eq!(1, Target::Pushgateway(String::new()).spool_max(1000));
+        assert_eq!(1000, Target::RemoteWrite(String::new()).spool_max(1000));
+        assert_eq!(Duration::from_secs(40), backoff(Duration::from_secs(10), 2));
+        assert_eq!(MAX_BACKOFF, backoff(Duration::from_secs(10), 10));
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use entropy::shannon_entropy;
+use log::*;
+
+/// Tells if a single path segment, like folder or file name, looks random
+pub trait RandomnessDetector {
+    fn name(&self) -> &'static str;
+
+    /// Scores path segment, meaning of the score depends on the detector
+    fn score(&self, segment: &str) -> f32;
+
+    /// Checks if the score means that segment is random
+    fn is_random(&self, score: f32) -> bool;
+}
+
+/// Creates detector by the name from configuration
+pub fn detector(name: &str, threshold: Option<f32>) -> Box<dyn RandomnessDetector> {
+    match name {
+        "entropy" => Box::new(Entropy::new(threshold.unwrap_or(3.4))),
+        "pattern" => Box::new(Pattern),
+        "ngram" => Box::new(NGram::trained(threshold.unwrap_or(3.0))),
+        _ => {
+            warn!("unknown randomness detector {}, using entropy", name);
+            Box::new(Entropy::new(threshold.unwrap_or(3.4)))
+        }
+    }
+}
+
+/// Replaces random-looking parts of a single path segment with placeholders,
+/// so that `prom_cnproc-0883569a23a4bd16` becomes `prom_cnproc-{hash}`.
+/// Hex, UUID and base64 tokens are replaced with any detector, and the
+/// detector decides only about segments, that have none of them.
+pub fn normalize(detector: &dyn RandomnessDetector, segment: &str) -> String {
+    let score = detector.score(segment);
+    let random = detector.is_random(score);
+    debug!("randomness detector={} segment={} score={} random={}",
+        detector.name(), segment, score, random);
+    let mut normalized = String::new();
+    let mut rest = segment;
+    while !rest.is_empty() {
+        if rest.len() >= 36 && rest.is_char_boundary(36) && is_uuid(&rest[..36]) {
+            normalized.push_str("{uuid}");
+            rest = &rest[36..];
+            continue;
+        }
+        let end = rest.find(['-', '_', '.']).unwrap_or(rest.len());
+        let token = &rest[..end];
+        if is_hex(token) {
+            normalized.push_str("{hash}");
+        } else if is_base64(token) {
+            normalized.push_str("{random}");
+        } else {
+            normalized.push_str(token);
+        }
+        // keep the separator as is
+        let next = rest[end..].chars().next().map(char::len_utf8).unwrap_or(0);
+        normalized.push_str(&rest[end..end + next]);
+        rest = &rest[end + next..];
+    }
+    if random && normalized == segment {
+        // detector knows better than simple patterns
+        return String::from("{random}");
+    }
+    normalized
+}
+
+/// Normalizes file name and prepends the deepest random folder, so that
+/// `/tmp/ZW50cm9weQo/top.py` becomes `{random}:top.py`. Label is a single
+/// segment of the tree, so it never has `/` in it.
+pub fn label(detector: &dyn RandomnessDetector, path: &str) -> String {
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let filename = normalize(detector, segments.pop().unwrap_or(""));
+    for dir in segments.iter().rev() {
+        let normalized = normalize(detector, dir);
+        if normalized != *dir {
+            return format!("{}:{}", normalized, filename);
+        }
+    }
+    filename
+}
+
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36 && s.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit(),
+    })
+}
+
+fn is_hex(token: &str) -> bool {
+    let hex = token.chars().all(|c| c.is_ascii_hexdigit());
+    let digits = token.chars().any(|c| c.is_ascii_digit());
+    let letters = token.chars().any(|c| c.is_ascii_alphabetic());
+    hex && ((token.len() >= 8 && digits && letters) || token.len() >= 16)
+}
+
+/// Minimum length of base64 token, as shorter names mix cases and digits too
+const BASE64_MIN: usize = 10;
+
+/// Random base64 has all three character classes in close amounts, so the
+/// entropy of classes is near log2(3), while names, like `libQt5Core`, are
+/// mostly lowercase
+const BASE64_CLASS_ENTROPY: f32 = 1.3;
+
+fn is_base64(token: &str) -> bool {
+    let alphabet = token.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '=');
+    if !alphabet || token.len() < BASE64_MIN {
+        return false;
+    }
+    let classes = token.chars().map(|c| match c {
+        'A'..='Z' => b'A',
+        'a'..='z' => b'a',
+        '0'..='9' => b'0',
+        _ => b'+',
+    }).collect::<Vec<u8>>();
+    shannon_entropy(&classes) >= BASE64_CLASS_ENTROPY
+}
+
+/// Shannon entropy of the most random token of a segment, in bits per
+/// character. Tokens are split by `-`, `_` and `.`, because separators add
+/// bits to any long name. Regular names repeat letters and stay below 3.4
+/// bits, while random tokens of 11 and more characters are above it.
+pub struct Entropy {
+    threshold: f32,
+}
+
+impl Entropy {
+    pub fn new(threshold: f32) -> Self {
+        Self{threshold}
+    }
+}
+
+impl RandomnessDetector for Entropy {
+    fn name(&self) -> &'static str {
+        "entropy"
+    }
+
+    fn score(&self, segment: &str) -> f32 {
+        segment
+            .split(['-', '_', '.'])
+            .map(|token| shannon_entropy(token.as_bytes()))
+            .fold(0.0, f32::max)
+    }
+
+    fn is_random(&self, score: f32) -> bool {
+        score > self.threshold
+    }
+}
+
+/// Looks for hex, UUID and base64 tokens, like `prom_cnproc-0883569a23a4bd16`
+pub struct Pattern;
+
+impl RandomnessDetector for Pattern {
+    fn name(&self) -> &'static str {
+        "pattern"
+    }
+
+    /// Returns fraction of characters, that belong to random-looking tokens
+    fn score(&self, segment: &str) -> f32 {
+        if segment.is_empty() {
+            return 0.0;
+        }
+        let random: usize = segment
+            .split(['-', '_', '.'])
+            .filter(|token| is_hex(token) || is_base64(token))
+            .map(str::len)
+            .sum();
+        random as f32 / segment.len() as f32
+    }
+
+    fn is_random(&self, score: f32) -> bool {
+        // even a short hash in the name makes it unique
+        score >= 0.3
+    }
+}
+
+/// Number of character classes, including the start of segment
+const CLASSES: usize = 7;
+const START: usize = 6;
+
+/// Maps character to vowel, consonant, uppercase, digit, separator or other
+fn class(c: char) -> usize {
+    match c {
+        'a' | 'e' | 'i' | 'o' | 'u' | 'y' => 0,
+        'a'..='z' => 1,
+        'A'..='Z' => 2,
+        '0'..='9' => 3,
+        '-' | '_' | '.' => 4,
+        _ => 5,
+    }
+}
+
+/// Character-class trigram model, that is trained on the file names of
+/// this host. Score is the average surprise in bits per character, so
+/// names, that don't look like anything installed, score higher.
+pub struct NGram {
+    trigrams: HashMap<[usize; 3], u32>,
+    bigrams: HashMap<[usize; 2], u32>,
+    threshold: f32,
+}
+
+impl NGram {
+    fn new(threshold: f32) -> Self {
+        Self{trigrams: HashMap::new(), bigrams: HashMap::new(), threshold}
+    }
+
+    /// Trains the model on names from system folders
+    pub fn trained(threshold: f32) -> Self {
+        let mut model = Self::new(threshold);
+        let mut names = 0;
+        for dir in ["/usr/bin", "/usr/sbin", "/usr/lib", "/usr/share", "/etc"].iter() {
+            names += model.train_dir(Path::new(dir), 1);
+        }
+        info!("trained randomness model on {} file names", names);
+        model
+    }
+
+    fn train_dir(&mut self, dir: &Path, depth: usize) -> usize {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+        let mut names = 0;
+        for entry in entries.flatten() {
+            if let Some(name) = entry.file_name().to_str() {
+                self.train(name);
+                names += 1;
+            }
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            if depth > 0 && is_dir {
+                names += self.train_dir(&entry.path(), depth - 1);
+            }
+        }
+        names
+    }
+
+    fn train(&mut self, name: &str) {
+        let mut prev = [START, START];
+        for c in name.chars() {
+            let curr = class(c);
+            *self.trigrams.entry([prev[0], prev[1], curr]).or_insert(0) += 1;
+            *self.bigrams.entry(prev).or_insert(0) += 1;
+            prev = [prev[1], curr];
+        }
+    }
+}
+
+impl RandomnessDetector for NGram {
+    fn name(&self) -> &'static str {
+        "ngram"
+    }
+
+    fn score(&self, segment: &str) -> f32 {
+        let mut prev = [START, START];
+        let mut bits = 0.0;
+        let mut len = 0;
+        for c in segment.chars() {
+            let curr = class(c);
+            let seen = self.trigrams.get(&[prev[0], prev[1], curr]).copied().unwrap_or(0);
+            let total = self.bigrams.get(&prev).copied().unwrap_or(0);
+            // additive smoothing for never seen sequences
+            let p = (seen as f32 + 1.0) / (total as f32 + CLASSES as f32);
+            bits -= p.log2();
+            len += 1;
+            prev = [prev[1], curr];
+        }
+        if len == 0 {
+            return 0.0;
+        }
+        bits / len as f32
+    }
+
+    fn is_random(&self, score: f32) -> bool {
+        score > self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::config::Config;
+
+    #[test]
+    fn entropies() {
+        let d = Entropy::new(3.4);
+        let random = |segment| d.is_random(d.score(segment));
+        assert!(random("ZW50cm9weQo"));
+        assert!(random("aGVsbG8gd29ybGQ"));
+        assert!(random("prom_cnproc-0883569a23a4bd16"));
+        assert!(!random("prom_cnproc"));
+        assert!(!random("NetworkManager"));
+        assert!(!random("unattended-upgrade-shutdown"));
+        assert!(!random("containerd-shim-runc-v2"));
+        assert!(!random("networkd-dispatcher"));
+    }
+
+    #[test]
+    fn default_detector() {
+        let config = Config::default();
+        let d = detector(&config.randomness, config.randomness_threshold);
+        assert!(d.is_random(d.score("ZW50cm9weQo")));
+        assert!(!d.is_random(d.score("systemd-journald")));
+        assert_eq!("{random}:any-shady-process", label(d.as_ref(), "/tmp/ZW50cm9weQo/any-shady-process"));
+    }
+
+    #[test]
+    fn patterns() {
+        let d = Pattern;
+        let random = |segment| d.is_random(d.score(segment));
+        assert!(random("prom_cnproc-0883569a23a4bd16"));
+        assert!(random("ZW50cm9weQo"));
+        assert!(random("f81d4fae-7dec-11d0-a765-00a0c91e6bf6"));
+        assert!(!random("prom_cnproc"));
+        assert!(!random("NetworkManager"));
+        assert!(!random("python3.11"));
+        assert!(!random("x86_64-linux-gnu"));
+        assert!(!random("libQt5Core"));
+        assert!(!random("Qt5Widgets"));
+        assert!(!random("python3Xyz"));
+    }
+
+    #[test]
+    fn ngrams() {
+        let mut d = NGram::new(3.0);
+        for name in ["bash", "python3", "systemd-logind", "cron", "sshd", "apt-get",
+                "ls", "prom-cnproc", "containerd", "dockerd", "node", "npm"].iter() {
+            d.train(name);
+        }
+        let random = d.score("ZW50cm9weQo");
+        let regular = d.score("process");
+        assert!(random > regular, "{} > {}", random, regular);
+        assert!(d.is_random(random));
+        assert!(!d.is_random(regular));
+    }
+
+    #[test]
+    fn placeholders() {
+        let d = Entropy::new(3.4);
+        assert_eq!("prom_cnproc-{hash}", normalize(&d, "prom_cnproc-0883569a23a4bd16"));
+        assert_eq!("{uuid}.scope", normalize(&d, "f81d4fae-7dec-11d0-a765-00a0c91e6bf6.scope"));
+        assert_eq!("{random}", normalize(&d, "ZW50cm9weQo"));
+        assert_eq!("python3.11", normalize(&d, "python3.11"));
+        assert_eq!("libc.so.6", normalize(&d, "libc.so.6"));
+    }
+
+    #[test]
+    fn labels() {
+        let d = Entropy::new(3.4);
+        assert_eq!("prom_cnproc-{hash}", label(&d, "/tmp/target/debug/deps/prom_cnproc-0883569a23a4bd16"));
+        assert_eq!("{random}:any-shady-process", label(&d, "/tmp/ZW50cm9weQo/any-shady-process"));
+        assert_eq!("{uuid}:run", label(&d, "/var/lib/f81d4fae-7dec-11d0-a765-00a0c91e6bf6/bin/run"));
+        assert_eq!("top.py", label(&d, "/opt/scripts/top.py"));
+        assert_eq!("{random}:top.py", label(&d, "/tmp/ZW50cm9weQo/top.py"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log::*;
+use metrics::{GaugeValue, Key, Recorder, Unit};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_util::layers::FanoutBuilder;
+use super::config::Config;
+use super::aggregator::Aggregator;
+use super::otlp;
+use super::push::{self, Spool, Target};
+use super::statsd::Statsd;
+
+/// Recorders, that keep totals of counters, so that saved totals can be added
+/// back after restart. StatsD isn't one of them, because it sends only increments.
+#[derive(Clone, Default)]
+pub struct Totals(pub Vec<Arc<dyn Recorder + Send + Sync>>);
+
+impl Totals {
+    pub fn increment_counter(&self, key: &Key, value: u64) {
+        for recorder in &self.0 {
+            recorder.increment_counter(key, value);
+        }
+    }
+}
+
+/// Lets the same recorder be in the fanout and in totals
+struct Shared(Arc<dyn Recorder + Send + Sync>);
+
+impl Recorder for Shared {
+    fn register_counter(&self, key: &Key, unit: Option<Unit>, description: Option<&'static str>) {
+        self.0.register_counter(key, unit, description)
+    }
+
+    fn register_gauge(&self, key: &Key, unit: Option<Unit>, description: Option<&'static str>) {
+        self.0.register_gauge(key, unit, description)
+    }
+
+    fn register_histogram(&self, key: &Key, unit: Option<Unit>, description: Option<&'static str>) {
+        self.0.register_histogram(key, unit, description)
+    }
+
+    fn increment_counter(&self, key: &Key, value: u64) {
+        self.0.increment_counter(key, value)
+    }
+
+    fn update_gauge(&self, key: &Key, value: GaugeValue) {
+        self.0.update_gauge(key, value)
+    }
+
+    fn record_histogram(&self, key: &Key, value: f64) {
+        self.0.record_histogram(key, value)
+    }
+}
+
+/// Installs global metrics recorder, that serves Prometheus scrapes
+/// and sends metrics to every other configured destination.
+/// Returns aggregator, if anything needs current values of metrics,
+/// and recorders, where saved counters are restored.
+pub fn install(config: &Config) -> Result<(Option<Aggregator>, Totals)> {
+    let mut fanout = FanoutBuilder::default();
+    let mut totals = Totals::default();
+    if config.prometheus {
+        let addr: SocketAddr = "127.0.0.1:9501"
+            .parse()
+            .expect("Unable to parse socket address");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (recorder, exporter) = {
+            let _guard = runtime.enter();
+            PrometheusBuilder::new()
+                .listen_address(addr)
+                .build_with_exporter()
+                .map_err(Error::other)?
+        };
+        thread::Builder::new()
+            .name(String::from("prom-cnproc-http"))
+            .spawn(move || {
+                if let Err(e) = runtime.block_on(exporter) {
+                    error!("Prometheus endpoint failed: {}", e);
+                }
+            })?;
+        let recorder: Arc<dyn Recorder + Send + Sync> = Arc::new(recorder);
+        totals.0.push(recorder.clone());
+        fanout = fanout.add_recorder(Shared(recorder));
+    }
+    let aggregator = Aggregator::new();
+    // state file keeps counters across restarts
+    let mut aggregated = config.state.is_some();
+    if let Some(endpoint) = &config.otlp {
+        let protocol = otlp::Protocol::parse(&config.otlp_protocol)?;
+        otlp::export(&aggregator, endpoint, protocol, Duration::from_secs(config.otlp_interval))?;
+        info!("pushing metrics to {} every {}s", endpoint, config.otlp_interval);
+        aggregated = true;
+    }
+    let targets = config.pushgateway.iter().map(|url| Target::Pushgateway(url.clone()))
+        .chain(config.remote_write.iter().map(|url| Target::RemoteWrite(url.clone())));
+    for target in targets {
+        let dir = config.push_buffer.as_ref().map(|dir| Path::new(dir).join(target.name()));
+        let spool = Spool::new(dir, target.spool_max(config.push_buffer_max))?;
+        info!("pushing metrics to {} every {}s", target.name(), config.push_interval);
+        push::start(target, &aggregator, Duration::from_secs(config.push_interval), spool)?;
+        aggregated = true;
+    }
+    if let Some(addr) = &config.statsd {
+        info!("sending metrics to {}", addr);
+        fanout = fanout.add_recorder(Statsd::new(addr)?);
+    }
+    let aggregator = match aggregated {
+        true => {
+            fanout = fanout.add_recorder(aggregator.clone());
+            totals.0.push(Arc::new(aggregator.clone()));
+            Some(aggregator)
+        }
+        false => None,
+    };
+    metrics::set_boxed_recorder(Box::new(fanout.build())).map_err(Error::other)?;
+    Ok((aggregator, totals))
+}
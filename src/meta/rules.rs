@@ -0,0 +1,218 @@
+use std::time::Duration;
+use log::*;
+use super::info::Process;
+
+/// Named check of the executed process, that triggers alerts
+pub struct Rule {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Receives the process and its parents, closest first
+    check: fn(&Process, &[&Process]) -> bool,
+}
+
+impl Rule {
+    pub fn matches(&self, prc: &Process, ancestry: &[&Process]) -> bool {
+        (self.check)(prc, ancestry)
+    }
+}
+
+/// Network facing daemons, databases and language runtimes, that are not expected to start shells
+const SERVERS: [&str; 18] = ["nginx", "apache2", "httpd", "lighttpd", "php-fpm", "java",
+    "node", "mysqld", "mariadbd", "postgres", "redis-server", "mongod", "tomcat", "dotnet",
+    "ruby", "python", "uwsgi", "gunicorn"];
+/// Tools, that download files
+const DOWNLOADERS: [&str; 2] = ["curl", "wget"];
+/// World writable folders, where downloaded payloads are usually put
+const WRITABLE: [&str; 3] = ["/tmp/", "/var/tmp/", "/dev/shm/"];
+/// How long after a download the exec from writable folder is suspicious
+const DOWNLOAD_WINDOW: Duration = Duration::from_secs(600);
+
+/// Tells if the process is network facing daemon or language runtime,
+/// like `php-fpm7.4` and similar versioned names
+pub fn is_server(prc: &Process) -> bool {
+    let name = prc.filename();
+    SERVERS.iter().any(|s| name.starts_with(s))
+}
+
+/// Tells if the process downloads files, so that its parent is marked
+pub fn is_download(prc: &Process) -> bool {
+    DOWNLOADERS.contains(&prc.filename())
+}
+
+static RULES: [Rule; 9] = [
+    Rule{
+        name: "unpackaged-under-sshd",
+        description: "binary, that doesn't belong to any package, is executed in SSH session",
+        check: |prc, ancestry| {
+            ancestry.iter().any(|p| p.filename() == "sshd") && prc.package() == "unpackaged"
+        },
+    },
+    Rule{
+        name: "random-tree",
+        description: "process tree has randomly named binaries or folders",
+        check: |prc, _| prc.tree.contains("{random}"),
+    },
+    Rule{
+        name: "shell-from-server",
+        description: "shell is started by network facing daemon, like nginx",
+        check: |prc, ancestry| {
+            prc.is_shell() && ancestry.first().is_some_and(|p| is_server(p))
+        },
+    },
+    Rule{
+        name: "socket-shell",
+        description: "interpreter has its input or output connected to socket, like reverse shell",
+        check: |prc, _| prc.is_interpreter() && prc.has_socket_stdio(),
+    },
+    Rule{
+        name: "download-exec",
+        description: "binary or script from world writable folder is executed after curl or wget",
+        check: |prc, ancestry| {
+            WRITABLE.iter().any(|w| prc.actual_runnable().starts_with(w))
+                && ancestry.iter().any(|p| p.downloaded.is_some_and(|d| d.elapsed() < DOWNLOAD_WINDOW))
+        },
+    },
+    Rule{
+        name: "namespace-escape",
+        description: "process of a container has got host mount or pid namespace",
+        check: |prc, _| prc.namespace_change == Some("escape"),
+    },
+    Rule{
+        name: "namespace-change",
+        description: "namespaces are changed by nsenter, unshare or anything else, but container runtime",
+        check: |prc, _| prc.namespace_change.is_some_and(|c| c != "escape"),
+    },
+    Rule{
+        name: "ld-preload",
+        description: "LD_PRELOAD or LD_LIBRARY_PATH points to libraries outside of standard folders",
+        check: |prc, _| !prc.environ.preload.is_empty(),
+    },
+    Rule{
+        name: "miner",
+        description: "process runs long and hot and looks like crypto-miner",
+        // scored on periodic samples of CPU time instead, see `miner::Miner`
+        check: |_, _| false,
+    },
+];
+
+/// Returns rules, that are enabled in configuration, or all of them,
+/// except the disabled ones
+pub fn enabled(names: Option<&[String]>, disabled: &[String]) -> Vec<&'static Rule> {
+    for name in names.unwrap_or_default().iter().chain(disabled) {
+        if !RULES.iter().any(|r| r.name == name) {
+            warn!("unknown rule {}", name);
+        }
+    }
+    RULES.iter()
+        .filter(|r| names.is_none_or(|names| names.iter().any(|n| n == r.name)))
+        .filter(|r| !disabled.iter().any(|n| n == r.name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::TempDir;
+
+    fn rule(name: &str) -> &'static Rule {
+        RULES.iter().find(|r| r.name == name).unwrap()
+    }
+
+    #[test]
+    fn shell_from_server() {
+        let nginx = Process::from(2, 1, "/usr/sbin/nginx", vec![]);
+        let fpm = Process::from(2, 1, "/usr/sbin/php-fpm7.4", vec![]);
+        let sshd = Process::from(2, 1, "/usr/sbin/sshd", vec![]);
+        let shell = Process::from(3, 2, "/bin/sh", vec![String::from("sh")]);
+        let top = Process::from(3, 2, "/usr/bin/top", vec![String::from("top")]);
+        let rule = rule("shell-from-server");
+        assert!(rule.matches(&shell, &[&nginx]));
+        assert!(rule.matches(&shell, &[&fpm]));
+        assert!(!rule.matches(&shell, &[&sshd]));
+        assert!(!rule.matches(&top, &[&nginx]));
+        assert!(!rule.matches(&shell, &[]));
+    }
+
+    #[test]
+    fn random_tree() {
+        let mut prc = Process::from(3, 2, "/tmp/x/top", vec![]);
+        prc.tree = String::from("/sshd/bash/{random}:top");
+        assert!(rule("random-tree").matches(&prc, &[]));
+        prc.tree = String::from("/sshd/bash/prom_cnproc-{hash}");
+        assert!(!rule("random-tree").matches(&prc, &[]));
+    }
+
+    /// Checks `sleep` as if it was the shell, that has stdin and stdout connected to the socket
+    fn socket_shell_on(socket: std::os::fd::OwnedFd) -> (bool, bool) {
+        let output = socket.try_clone().unwrap();
+        let mut child = std::process::Command::new("sleep").arg("10")
+            .stdin(std::process::Stdio::from(socket))
+            .stdout(std::process::Stdio::from(output))
+            .spawn().unwrap();
+        let shell = Process::from(child.id() as i32, 1, "/bin/sh", vec![]);
+        let top = Process::from(child.id() as i32, 1, "/usr/bin/top", vec![]);
+        let matched = rule("socket-shell").matches(&shell, &[]);
+        let other = rule("socket-shell").matches(&top, &[]);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        (matched, other)
+    }
+
+    #[test]
+    fn socket_shell() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let theirs = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_ours, _) = listener.accept().unwrap();
+        let (matched, other) = socket_shell_on(theirs.into());
+        assert!(matched);
+        assert!(!other);
+
+        // like service with output going to journald
+        let (_ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (matched, _) = socket_shell_on(theirs.into());
+        assert!(!matched);
+        assert!(!rule("socket-shell").matches(&Process::from(std::process::id() as i32, 1, "/bin/sh", vec![]), &[]));
+    }
+
+    #[test]
+    fn download_exec() {
+        let mut shell = Process::from(2, 1, "/bin/bash", vec![]);
+        let payload = Process::from(3, 2, "/dev/shm/x", vec![]);
+        let rule = rule("download-exec");
+        assert!(!rule.matches(&payload, &[&shell]));
+        shell.downloaded = Some(std::time::Instant::now());
+        assert!(rule.matches(&payload, &[&shell]));
+        assert!(!rule.matches(&Process::from(3, 2, "/usr/bin/top", vec![]), &[&shell]));
+        // scripts are run by interpreters from packaged folders
+        let tmp = TempDir::new("download-exec");
+        let script = tmp.join("payload.sh");
+        std::fs::write(&script, "id\n").unwrap();
+        let script = script.to_str().unwrap().to_string();
+        let argv = vec![String::from("sh"), script];
+        assert!(rule.matches(&Process::from(3, 2, "/usr/bin/sh", argv), &[&shell]));
+        assert!(is_download(&Process::from(4, 2, "/usr/bin/wget", vec![])));
+    }
+
+    #[test]
+    fn namespaces() {
+        let mut prc = Process::from(3, 2, "/usr/bin/bash", vec![]);
+        assert!(!rule("namespace-escape").matches(&prc, &[]));
+        prc.namespace_change = Some("escape");
+        assert!(rule("namespace-escape").matches(&prc, &[]));
+        assert!(!rule("namespace-change").matches(&prc, &[]));
+        prc.namespace_change = Some("nsenter");
+        assert!(rule("namespace-change").matches(&prc, &[]));
+    }
+
+    #[test]
+    fn enables_rules() {
+        assert_eq!(RULES.len(), enabled(None, &[]).len());
+        let names = vec![String::from("random-tree"), String::from("nonexistent")];
+        let rules = enabled(Some(&names), &[]);
+        assert_eq!(1, rules.len());
+        assert_eq!("random-tree", rules[0].name);
+        let disabled = vec![String::from("socket-shell")];
+        assert_eq!(RULES.len() - 1, enabled(None, &disabled).len());
+        assert!(enabled(Some(&names), &names[..1]).is_empty());
+    }
+}
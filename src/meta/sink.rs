@@ -0,0 +1,177 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::*;
+use super::config::Config;
+use super::event::Event;
+use super::otlp;
+use super::stream::Stream;
+use super::syslog::{Journald, Syslog};
+
+/// Destination for process events
+pub trait Sink {
+    fn name(&self) -> &'static str;
+
+    fn emit(&mut self, event: &Event) -> Result<()>;
+}
+
+/// Creates sinks, that are enabled in configuration
+pub fn from_config(config: &Config) -> Result<Vec<Box<dyn Sink>>> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    match config.json.as_deref() {
+        None => {}
+        Some("stdout") => sinks.push(Box::new(JsonLines::new(io::stdout()))),
+        Some(path) => {
+            let file = RotatingFile::open(PathBuf::from(path), config.json_max_bytes, config.json_keep)?;
+            info!("writing events to {}", path);
+            sinks.push(Box::new(JsonLines::new(file)));
+        }
+    }
+    let socket = config.syslog_socket.as_deref();
+    match config.syslog.as_deref() {
+        None => {}
+        Some("syslog") => {
+            let path = socket.unwrap_or("/dev/log");
+            sinks.push(Box::new(Syslog::new(PathBuf::from(path))?));
+        }
+        Some("journald") => {
+            let path = socket.unwrap_or("/run/systemd/journal/socket");
+            sinks.push(Box::new(Journald::new(PathBuf::from(path))?));
+        }
+        Some(other) => warn!("CNPROC_SYSLOG={} is neither syslog nor journald", other),
+    }
+    match (config.otlp.as_deref(), config.otlp_logs) {
+        (Some(endpoint), true) => {
+            let interval = Duration::from_secs(config.otlp_interval);
+            let protocol = otlp::Protocol::parse(&config.otlp_protocol)?;
+            sinks.push(Box::new(otlp::Logs::new(endpoint, protocol, interval)?));
+        }
+        (None, true) => warn!("CNPROC_OTLP_LOGS requires CNPROC_OTLP"),
+        _ => {}
+    }
+    if let Some(path) = &config.socket {
+        sinks.push(Box::new(Stream::listen(Path::new(path))?));
+        info!("streaming events on {}", path);
+    }
+    Ok(sinks)
+}
+
+/// Writes one JSON object per line
+pub struct JsonLines<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        Self{out}
+    }
+}
+
+impl<W: Write> Sink for JsonLines<W> {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        // single write, so that rotation never splits the line
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.out.flush()
+    }
+}
+
+/// File, that is renamed to `<path>.1` once it grows over the limit,
+/// keeping at most the configured number of older files
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_bytes: u64, keep: usize) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self{path, max_bytes, keep, file, written})
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let older = self.rotated(n);
+                if older.exists() {
+                    fs::rename(&older, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::event::Kind;
+    use super::super::info::Process;
+    use super::super::testing::TempDir;
+
+    #[test]
+    fn json_lines() {
+        let prc = Process::from(3, 2, "/usr/bin/top", vec![String::from("top")]);
+        let mut sink = JsonLines::new(vec![]);
+        sink.emit(&Event::new(Kind::Exec, &prc)).unwrap();
+        sink.emit(&Event::new(Kind::Exit, &prc)).unwrap();
+        let out = String::from_utf8(sink.out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with(r#"{"kind":"exec","#));
+    }
+
+    #[test]
+    fn rotates_files() {
+        let dir = TempDir::new("rotate");
+        let path = dir.join("events.json");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"].iter() {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let current = fs::read_to_string(&path).unwrap();
+        let first = fs::read_to_string(dir.join("events.json.1")).unwrap();
+        let second = fs::read_to_string(dir.join("events.json.2")).unwrap();
+        let third = dir.join("events.json.3").exists();
+        assert_eq!("fourth\n", current);
+        assert_eq!("third\n", first);
+        assert_eq!("second\n", second);
+        assert!(!third);
+    }
+}
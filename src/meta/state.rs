@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::*;
+use metrics::{Key, Label};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use super::aggregator::Aggregator;
+use super::info::{self, Process};
+use super::recorder::Totals;
+
+const VERSION: u32 = 1;
+
+/// History of the tree on this host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tree {
+    /// Seconds since epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub execs: u64,
+    pub exits: u64,
+}
+
+/// Running process, that is recognized after restart by its start time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Running {
+    pub pid: i32,
+    /// Clock ticks after boot, from `/proc/<pid>/stat`
+    pub ticks: u64,
+    pub label: String,
+    pub tree: String,
+    /// Seconds since epoch
+    pub started: f64,
+}
+
+/// Counter with its labels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Counter {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: u64,
+}
+
+/// Contents of the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct File {
+    pub version: u32,
+    pub trees: BTreeMap<String, Tree>,
+    pub running: Vec<Running>,
+    pub counters: Vec<Counter>,
+}
+
+impl File {
+    /// Loads everything, that is readable, so that a single broken entry
+    /// doesn't lose the rest of the state. Unreadable file is kept aside.
+    pub fn load(path: &Path) -> Self {
+        let raw = match fs::read(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("cannot read state from {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        let value: Value = match serde_json::from_slice(&raw) {
+            Ok(value) => value,
+            Err(e) => {
+                let mut corrupt = path.as_os_str().to_owned();
+                corrupt.push(".corrupt");
+                warn!("state in {} is corrupt, moving it to {:?}: {}", path.display(), corrupt, e);
+                if let Err(e) = fs::rename(path, &corrupt) {
+                    warn!("cannot move corrupt state: {}", e);
+                }
+                return Self::default();
+            }
+        };
+        let version = value["version"].as_u64().unwrap_or(0);
+        if version != VERSION as u64 {
+            warn!("ignoring state in {} with version {}, expected {}", path.display(), version, VERSION);
+            return Self::default();
+        }
+        let mut skipped = 0;
+        let mut trees = BTreeMap::new();
+        for (tree, entry) in value["trees"].as_object().into_iter().flatten() {
+            match serde_json::from_value(entry.clone()) {
+                Ok(entry) => {
+                    trees.insert(tree.clone(), entry);
+                }
+                Err(_) => skipped += 1,
+            }
+        }
+        let entries = |section: &str| -> Vec<Value> {
+            value[section].as_array().cloned().unwrap_or_default()
+        };
+        let running: Vec<Running> = parsed(entries("running"), &mut skipped);
+        let counters: Vec<Counter> = parsed(entries("counters"), &mut skipped);
+        if skipped > 0 {
+            warn!("skipped {} broken entries of state in {}", skipped, path.display());
+        }
+        Self{version: VERSION, trees, running, counters}
+    }
+}
+
+fn parsed<T: for<'de> Deserialize<'de>>(values: Vec<Value>, skipped: &mut usize) -> Vec<T> {
+    let mut parsed = vec![];
+    for value in values {
+        match serde_json::from_value(value) {
+            Ok(item) => parsed.push(item),
+            Err(_) => *skipped += 1,
+        }
+    }
+    parsed
+}
+
+/// Writes to the temporary file and renames it, so that readers never see partial file
+pub fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Keeps history of trees, running processes and counters across restarts
+pub struct State {
+    path: PathBuf,
+    interval: Duration,
+    trees: BTreeMap<String, Tree>,
+    saved: Instant,
+}
+
+impl State {
+    /// Loads the state and returns processes, that are still running
+    pub fn open(path: PathBuf, interval: Duration) -> (Self, Vec<Running>, Vec<Counter>) {
+        let file = File::load(&path);
+        info!("loaded {} trees and {} running processes from {}",
+            file.trees.len(), file.running.len(), path.display());
+        let running = file.running.into_iter()
+            .filter(|r| info::start_ticks(r.pid) == Some(r.ticks))
+            .collect();
+        let state = Self{path, interval, trees: file.trees, saved: Instant::now()};
+        (state, running, file.counters)
+    }
+
+    pub fn exec(&mut self, tree: &str, now: u64) {
+        let entry = self.trees.entry(String::from(tree))
+            .or_insert(Tree{first_seen: now, last_seen: now, execs: 0, exits: 0});
+        entry.last_seen = now;
+        entry.execs += 1;
+    }
+
+    pub fn exit(&mut self, tree: &str, now: u64) {
+        if let Some(entry) = self.trees.get_mut(tree) {
+            entry.last_seen = now;
+            entry.exits += 1;
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.saved.elapsed() >= self.interval
+    }
+
+    pub fn save(&mut self, pids: &HashMap<i32, Process>, aggregator: Option<&Aggregator>) -> Result<()> {
+        self.saved = Instant::now();
+        let running = pids.values()
+            .filter_map(|prc| Some(Running{
+                pid: prc.pid,
+                ticks: info::start_ticks(prc.pid)?,
+                label: prc.label.clone(),
+                tree: prc.tree.clone(),
+                started: prc.started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            }))
+            .collect();
+        let counters = match aggregator {
+            Some(aggregator) => aggregator.store().counters.iter()
+                .map(|(key, value)| Counter{
+                    name: String::from(key.name()),
+                    labels: key.labels().map(|l| (String::from(l.key()), String::from(l.value()))).collect(),
+                    value: *value,
+                })
+                .collect(),
+            None => vec![],
+        };
+        let file = File{version: VERSION, trees: self.trees.clone(), running, counters};
+        write(&self.path, &serde_json::to_vec(&file)?)
+    }
+}
+
+impl Running {
+    /// Rediscovers the process, keeping its label, tree and start time
+    pub fn restore(&self) -> Result<Process> {
+        let mut prc = Process::new(self.pid)?;
+        prc.label = self.label.clone();
+        prc.tree = self.tree.clone();
+        prc.started = UNIX_EPOCH + Duration::from_secs_f64(self.started);
+        let age = SystemTime::now().duration_since(prc.started).unwrap_or_default();
+        prc.start = Instant::now().checked_sub(age).unwrap_or(prc.start);
+        Ok(prc)
+    }
+}
+
+impl Counter {
+    /// Adds the saved value to the counter, so that it continues where it was
+    pub fn restore(&self, totals: &Totals) {
+        let labels: Vec<Label> = self.labels.iter().map(|(k, v)| Label::new(k.clone(), v.clone())).collect();
+        totals.increment_counter(&Key::from_parts(self.name.clone(), labels), self.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::TempDir;
+    use metrics::Recorder;
+    use std::sync::Arc;
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.json");
+        let (mut state, running, counters) = State::open(path.clone(), Duration::from_secs(60));
+        assert!(running.is_empty() && counters.is_empty());
+        state.exec("/sshd/bash", 10);
+        state.exec("/sshd/bash", 20);
+        state.exit("/sshd/bash", 30);
+        let mut pids = HashMap::new();
+        let mut prc = Process::new(std::process::id() as i32).unwrap();
+        prc.tree = String::from("/cargo/test");
+        prc.started -= Duration::from_secs(100);
+        pids.insert(prc.pid, prc);
+        state.save(&pids, None).unwrap();
+
+        let (state, running, _) = State::open(path.clone(), Duration::from_secs(60));
+        assert_eq!(Tree{first_seen: 10, last_seen: 30, execs: 2, exits: 1}, state.trees["/sshd/bash"]);
+        assert_eq!(1, running.len());
+        let restored = running[0].restore().unwrap();
+        assert_eq!("/cargo/test", restored.tree);
+        assert!(restored.start.elapsed() >= Duration::from_secs(99));
+    }
+
+    #[test]
+    fn restores_counters() {
+        let dir = TempDir::new("state-counters");
+        let path = dir.join("state.json");
+        let key = Key::from_parts("process_exec_total", vec![Label::new("tree", "/sshd/bash")]);
+        let saved = Aggregator::new();
+        saved.increment_counter(&key, 5);
+        let (mut state, _, _) = State::open(path.clone(), Duration::from_secs(60));
+        state.save(&HashMap::new(), Some(&saved)).unwrap();
+
+        let (_, _, counters) = State::open(path, Duration::from_secs(60));
+        let restored = Aggregator::new();
+        restored.increment_counter(&key, 1);
+        for counter in &counters {
+            counter.restore(&Totals(vec![Arc::new(restored.clone())]));
+        }
+        assert_eq!(Some(&6), restored.store().counters.get(&key));
+    }
+
+    #[test]
+    fn tolerates_corruption() {
+        let dir = TempDir::new("state-broken");
+        let path = dir.join("state.json");
+        fs::write(&path, r#"{"version":1,"trees":{"/a":{"first_seen":1,"last_seen":2,"execs":3,"exits":0},
+            "/b":{"first_seen":"x"}},"running":[{"pid":1}],"counters":[]}"#).unwrap();
+        let file = File::load(&path);
+        assert_eq!(vec!["/a"], file.trees.keys().collect::<Vec<_>>());
+        assert!(file.running.is_empty());
+
+        fs::write(&path, r#"{"version":1,"trees":{"/a":"#).unwrap();
+        assert!(File::load(&path).trees.is_empty());
+        let mut corrupt = path.as_os_str().to_owned();
+        corrupt.push(".corrupt");
+        assert!(!path.exists());
+        assert!(Path::new(&corrupt).exists());
+
+        fs::write(&path, r#"{"version":99,"trees":{"/a":{"first_seen":1,"last_seen":2,"execs":3,"exits":0}}}"#).unwrap();
+        assert!(File::load(&path).trees.is_empty());
+    }
+}
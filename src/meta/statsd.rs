@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use log::*;
+use metrics::{GaugeValue, Key, Recorder, Unit};
+
+/// Sends every metric update over UDP in DogStatsD format,
+/// where metric labels become tags, like `process:1|g|#tree:/sshd/bash,state:RUNNING`
+pub struct Statsd {
+    socket: UdpSocket,
+    /// DogStatsD has only absolute gauges
+    gauges: Mutex<HashMap<Key, f64>>,
+}
+
+impl Statsd {
+    pub fn new(addr: &str) -> Result<Self> {
+        let addr = addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("cannot resolve {}", addr)))?;
+        let local = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self{socket, gauges: Mutex::new(HashMap::new())})
+    }
+
+    fn send(&self, key: &Key, value: f64, kind: &str) {
+        let line = line(key, value, kind);
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("cannot send {}: {}", line, e);
+        }
+    }
+}
+
+/// Formats the metric update, replacing characters, that cannot be in tags
+fn line(key: &Key, value: f64, kind: &str) -> String {
+    let tags: Vec<String> = key.labels()
+        .map(|l| format!("{}:{}", l.key(), l.value().replace([',', '|', '#'], "_")))
+        .collect();
+    match tags.is_empty() {
+        true => format!("{}:{}|{}", key.name(), value, kind),
+        false => format!("{}:{}|{}|#{}", key.name(), value, kind, tags.join(",")),
+    }
+}
+
+impl Recorder for Statsd {
+    fn register_counter(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_gauge(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn increment_counter(&self, key: &Key, value: u64) {
+        self.send(key, value as f64, "c");
+    }
+
+    fn update_gauge(&self, key: &Key, value: GaugeValue) {
+        let value = match value {
+            GaugeValue::Absolute(value) => {
+                self.gauges.lock().unwrap().insert(key.clone(), value);
+                value
+            }
+            relative => {
+                let mut gauges = self.gauges.lock().unwrap();
+                let gauge = gauges.entry(key.clone()).or_default();
+                *gauge = relative.update_value(*gauge);
+                *gauge
+            }
+        };
+        self.send(key, value, "g");
+    }
+
+    fn record_histogram(&self, key: &Key, value: f64) {
+        self.send(key, value, "h");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::Label;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_lines() {
+        let key = Key::from_parts("process", vec![Label::new("tree", "/a,b|c"), Label::new("state", "RUNNING")]);
+        assert_eq!("process:1|g|#tree:/a_b_c,state:RUNNING", line(&key, 1., "g"));
+        assert_eq!("process_hash_dropped_total:1|c", line(&Key::from_name("process_hash_dropped_total"), 1., "c"));
+    }
+
+    #[test]
+    fn sends_datagrams() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let statsd = Statsd::new(&server.local_addr().unwrap().to_string()).unwrap();
+        let key = Key::from_parts("process_seconds", vec![Label::new("tree", "/sshd/bash")]);
+        statsd.record_histogram(&key, 0.5);
+        let gauge = Key::from_name("process_base_entries");
+        statsd.update_gauge(&gauge, GaugeValue::Absolute(3.));
+        statsd.update_gauge(&gauge, GaugeValue::Increment(2.));
+
+        let mut received = vec![];
+        let mut buf = [0; 1024];
+        for _ in 0..3 {
+            let len = server.recv(&mut buf).unwrap();
+            received.push(String::from_utf8_lossy(&buf[..len]).to_string());
+        }
+        assert_eq!(vec![
+            "process_seconds:0.5|h|#tree:/sshd/bash",
+            "process_base_entries:3|g",
+            "process_base_entries:5|g",
+        ], received);
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Error, Result, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use log::*;
+use metrics::{counter, gauge};
+use serde::Deserialize;
+use super::event::{Event, Kind};
+use super::sink::Sink;
+
+/// Maximum number of events waiting to be written to a single client
+const QUEUE_SIZE: usize = 1024;
+/// How long to wait for the subscription line
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of connected clients, each of which has its own thread
+const MAX_CLIENTS: usize = 16;
+/// How often idle clients are checked for hang-ups
+const HANGUP_CHECK: Duration = Duration::from_secs(1);
+
+/// Subscription, that is sent by the client as the first JSON line.
+/// Empty object subscribes to every event.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Event kinds, like `exec` and `exit`
+    kinds: Vec<Kind>,
+    /// Prefix of the tree, like `/sshd`
+    tree: Option<String>,
+    /// Prefix of the executed binary, like `/tmp/`
+    exe: Option<String>,
+    user: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, event: &Event) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && self.tree.as_ref().is_none_or(|t| event.tree.starts_with(t.as_str()))
+            && self.exe.as_ref().is_none_or(|e| event.exe.starts_with(e.as_str()))
+            && self.user.as_ref().is_none_or(|u| &event.user == u)
+    }
+}
+
+struct Client {
+    filter: Filter,
+    queue: SyncSender<Arc<String>>,
+    /// Events, that were not queued, because the client is too slow
+    dropped: Arc<AtomicU64>,
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// Serves newline-delimited JSON events to local subscribers
+pub struct Stream {
+    clients: Clients,
+}
+
+impl Stream {
+    /// Listens on the socket, that is accessible to root and its group
+    pub fn listen(path: &Path) -> Result<Self> {
+        if path.exists() {
+            // left over from the previous run
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+        let clients: Clients = Arc::new(Mutex::new(vec![]));
+        let shared = clients.clone();
+        let connected = Arc::new(AtomicUsize::new(0));
+        thread::Builder::new()
+            .name(String::from("prom-cnproc-stream"))
+            .spawn(move || {
+                for conn in listener.incoming() {
+                    match conn {
+                        Ok(conn) => accept(conn, &shared, &connected),
+                        Err(e) => warn!("cannot accept stream client: {}", e),
+                    }
+                }
+            })?;
+        Ok(Self{clients})
+    }
+}
+
+/// Serves the client in its own thread, unless there are too many of them
+fn accept(conn: UnixStream, clients: &Clients, connected: &Arc<AtomicUsize>) {
+    if connected.fetch_add(1, Ordering::Relaxed) >= MAX_CLIENTS {
+        connected.fetch_sub(1, Ordering::Relaxed);
+        counter!("process_stream_rejected_total", 1);
+        let reply = serde_json::json!({"error": format!("more than {} clients", MAX_CLIENTS)});
+        let _ = writeln!(&conn, "{}", reply);
+        return;
+    }
+    let clients = clients.clone();
+    let done = connected.clone();
+    let spawned = thread::Builder::new()
+        .name(String::from("prom-cnproc-stream-client"))
+        .spawn(move || {
+            serve(conn, &clients);
+            done.fetch_sub(1, Ordering::Relaxed);
+        });
+    if let Err(e) = spawned {
+        connected.fetch_sub(1, Ordering::Relaxed);
+        warn!("cannot start stream client thread: {}", e);
+    }
+}
+
+fn serve(conn: UnixStream, clients: &Clients) {
+    let (rx, dropped) = match subscribe(&conn, clients) {
+        Ok(subscribed) => subscribed,
+        Err(e) => {
+            debug!("stream client failed to subscribe: {}", e);
+            let reply = serde_json::json!({"error": e.to_string()});
+            let _ = writeln!(&conn, "{}", reply);
+            return;
+        }
+    };
+    if let Err(e) = write(conn, rx, &dropped) {
+        debug!("stream client disconnected: {}", e);
+    }
+    // events may not match the filter for a long time, so the client is removed here
+    let mut clients = clients.lock().unwrap();
+    clients.retain(|client| !Arc::ptr_eq(&client.dropped, &dropped));
+    gauge!("process_stream_clients", clients.len() as f64);
+}
+
+fn subscribe(conn: &UnixStream, clients: &Clients) -> Result<(Receiver<Arc<String>>, Arc<AtomicU64>)> {
+    conn.set_read_timeout(Some(SUBSCRIBE_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(conn).read_line(&mut line)?;
+    let filter: Filter = match line.trim() {
+        "" => Filter::default(),
+        json => serde_json::from_str(json)?,
+    };
+    debug!("stream client subscribed with {:?}", filter);
+    let (queue, rx) = sync_channel(QUEUE_SIZE);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let mut clients = clients.lock().unwrap();
+    clients.push(Client{filter, queue, dropped: dropped.clone()});
+    gauge!("process_stream_clients", clients.len() as f64);
+    Ok((rx, dropped))
+}
+
+/// Writes events to the client, telling it how many were dropped in between.
+/// Returns once the client hangs up, even if there's nothing to write.
+fn write(mut conn: UnixStream, rx: Receiver<Arc<String>>, dropped: &AtomicU64) -> Result<()> {
+    loop {
+        let line = match rx.recv_timeout(HANGUP_CHECK) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) if hung_up(&conn)? => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        let missed = dropped.swap(0, Ordering::Relaxed);
+        if missed > 0 {
+            writeln!(conn, "{}", serde_json::json!({"kind": "dropped", "count": missed}))?;
+        }
+        conn.write_all(line.as_bytes())?;
+    }
+}
+
+/// Tells if the other side has closed the connection without reading from it
+fn hung_up(conn: &UnixStream) -> Result<bool> {
+    let mut fd = libc::pollfd{fd: conn.as_raw_fd(), events: libc::POLLIN, revents: 0};
+    if unsafe { libc::poll(&mut fd, 1, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(fd.revents & (libc::POLLHUP | libc::POLLERR) != 0)
+}
+
+impl Sink for Stream {
+    fn name(&self) -> &'static str {
+        "stream"
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(());
+        }
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        let line = Arc::new(line);
+        let before = clients.len();
+        clients.retain(|client| {
+            if !client.filter.matches(event) {
+                return true;
+            }
+            match client.queue.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    client.dropped.fetch_add(1, Ordering::Relaxed);
+                    counter!("process_stream_dropped_total", 1);
+                    true
+                }
+                // writer has stopped, because the client went away
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        if clients.len() != before {
+            gauge!("process_stream_clients", clients.len() as f64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::info::Process;
+    use super::super::testing::TempDir;
+    use std::time::Instant;
+
+    fn event(kind: Kind, exe: &str, tree: &str) -> Event {
+        let mut prc = Process::from(3, 2, exe, vec![]);
+        prc.tree = String::from(tree);
+        Event::new(kind, &prc)
+    }
+
+    #[test]
+    fn filters() {
+        let filter: Filter = serde_json::from_str(r#"{"kinds":["exec"],"tree":"/sshd"}"#).unwrap();
+        assert!(filter.matches(&event(Kind::Exec, "/usr/bin/top", "/sshd/bash/top")));
+        assert!(!filter.matches(&event(Kind::Exit, "/usr/bin/top", "/sshd/bash/top")));
+        assert!(!filter.matches(&event(Kind::Exec, "/usr/bin/top", "/cron/top")));
+        assert!(Filter::default().matches(&event(Kind::Fork, "/usr/bin/top", "/cron/top")));
+        assert!(serde_json::from_str::<Filter>(r#"{"pid":1}"#).is_err());
+    }
+
+    #[test]
+    fn streams_events() {
+        let dir = TempDir::new("stream");
+        let path = dir.join("stream.sock");
+        let mut stream = Stream::listen(&path).unwrap();
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.write_all(b"{\"kinds\":[\"exec\"]}\n").unwrap();
+        let start = Instant::now();
+        while stream.clients.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        stream.emit(&event(Kind::Exit, "/usr/bin/top", "/sshd/top")).unwrap();
+        stream.emit(&event(Kind::Exec, "/usr/bin/top", "/sshd/top")).unwrap();
+        let mut line = String::new();
+        BufReader::new(&conn).read_line(&mut line).unwrap();
+        assert!(line.starts_with(r#"{"kind":"exec","#), "{}", line);
+
+        // hang-up is noticed without any matching events
+        drop(conn);
+        while !stream.clients.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn limits_clients() {
+        let dir = TempDir::new("stream-limit");
+        let path = dir.join("stream.sock");
+        let _stream = Stream::listen(&path).unwrap();
+        let waiting: Vec<UnixStream> = (0..MAX_CLIENTS).map(|_| UnixStream::connect(&path).unwrap()).collect();
+        let conn = UnixStream::connect(&path).unwrap();
+        let mut line = String::new();
+        BufReader::new(&conn).read_line(&mut line).unwrap();
+        assert_eq!(format!("{{\"error\":\"more than {} clients\"}}\n", MAX_CLIENTS), line);
+        assert_eq!(MAX_CLIENTS, waiting.len());
+    }
+
+    #[test]
+    fn counts_drops() {
+        let (queue, rx) = sync_channel(1);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let client = Client{filter: Filter::default(), queue, dropped: dropped.clone()};
+        let mut stream = Stream{clients: Arc::new(Mutex::new(vec![client]))};
+        for _ in 0..3 {
+            stream.emit(&event(Kind::Exec, "/usr/bin/top", "/top")).unwrap();
+        }
+        assert_eq!(2, dropped.load(Ordering::Relaxed));
+        drop(rx);
+        stream.emit(&event(Kind::Exec, "/usr/bin/top", "/top")).unwrap();
+        assert!(stream.clients.lock().unwrap().is_empty());
+    }
+}
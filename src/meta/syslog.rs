@@ -0,0 +1,200 @@
+use std::io::Result;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use serde_json::Value;
+use super::event::{rfc3339, Event};
+use super::info::hostname;
+use super::sink::Sink;
+
+const APP_NAME: &str = "prom-cnproc";
+/// Example enterprise number from RFC 5612, reserved for documentation
+const SD_ID: &str = "cnproc@32473";
+/// daemon facility
+const FACILITY: u8 = 3;
+
+/// Returns event fields as pairs of name and plain text value,
+/// where `argv` is joined with spaces
+fn fields(event: &Event) -> Result<Vec<(String, String)>> {
+    let value = serde_json::to_value(event)?;
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return Ok(vec![]),
+    };
+    Ok(object.into_iter().map(|(key, value)| {
+        let text = match value {
+            Value::String(s) => s,
+            Value::Array(items) => items.iter()
+                .map(|item| item.as_str().map(String::from).unwrap_or_else(|| item.to_string()))
+                .collect::<Vec<_>>()
+                .join(" "),
+            other => other.to_string(),
+        };
+        (key, text)
+    }).collect())
+}
+
+/// Human readable summary of the event
+fn message(event: &Event) -> String {
+    match (event.exit_code, event.exit_signal) {
+        (Some(code), _) => format!("{} {} pid={} code={}", event.tree, event.exe, event.pid, code),
+        (_, Some(signal)) => format!("{} {} pid={} signal={}", event.tree, event.exe, event.pid, signal),
+        _ => format!("{} {} pid={}", event.tree, event.exe, event.pid),
+    }
+}
+
+/// Sends RFC 5424 messages with event fields as structured data
+/// to the local syslog socket
+pub struct Syslog {
+    socket: UnixDatagram,
+    path: PathBuf,
+    hostname: String,
+}
+
+impl Syslog {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        Ok(Self{socket, path, hostname: hostname()})
+    }
+
+    fn format(&self, event: &Event) -> Result<String> {
+        let mut params = String::new();
+        for (key, value) in fields(event)? {
+            // PARAM-VALUE has to escape '"', '\' and ']'
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]");
+            params.push_str(&format!(" {}=\"{}\"", key, escaped));
+        }
+        Ok(format!("<{}>1 {} {} {} {} {} [{}{}] {}",
+            FACILITY * 8 + event.kind.severity(), rfc3339(std::time::SystemTime::now()), self.hostname, APP_NAME,
+            std::process::id(), event.kind.as_str(), SD_ID, params, message(event)))
+    }
+}
+
+impl Sink for Syslog {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        let line = self.format(event)?;
+        self.socket.send_to(line.as_bytes(), &self.path)?;
+        Ok(())
+    }
+}
+
+/// Sends events to journald over its native protocol,
+/// with every field prefixed as `CNPROC_TREE`, `CNPROC_EXE` and so on
+pub struct Journald {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Journald {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        Ok(Self{socket, path})
+    }
+
+    fn format(&self, event: &Event) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        let mut field = |key: &str, value: &str| {
+            buf.extend_from_slice(key.as_bytes());
+            if value.contains('\n') {
+                // values with newlines are sent with explicit length
+                buf.push(b'\n');
+                buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                buf.push(b'=');
+            }
+            buf.extend_from_slice(value.as_bytes());
+            buf.push(b'\n');
+        };
+        field("MESSAGE", &message(event));
+        field("PRIORITY", &event.kind.severity().to_string());
+        field("SYSLOG_IDENTIFIER", APP_NAME);
+        for (key, value) in fields(event)? {
+            field(&format!("CNPROC_{}", key.to_uppercase()), &value);
+        }
+        Ok(buf)
+    }
+}
+
+impl Sink for Journald {
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        let datagram = self.format(event)?;
+        self.socket.send_to(&datagram, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::event::Kind;
+    use super::super::info::Process;
+    use super::super::testing::TempDir;
+
+    fn event() -> Event {
+        let argv = vec![String::from("top"), String::from("-b")];
+        let mut prc = Process::from(3, 2, "/usr/bin/top", argv);
+        prc.tree = String::from("/sshd/bash/top");
+        let mut event = Event::new(Kind::Exit, &prc);
+        event.exit_code = Some(1);
+        event
+    }
+
+    fn listener(name: &str) -> (UnixDatagram, PathBuf, TempDir) {
+        let dir = TempDir::new(name);
+        let path = dir.join("socket");
+        (UnixDatagram::bind(&path).unwrap(), path, dir)
+    }
+
+    fn received(listener: &UnixDatagram) -> String {
+        let mut buf = vec![0; 8192];
+        let len = listener.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).to_string()
+    }
+
+    #[test]
+    fn rfc5424() {
+        let (listener, path, _dir) = listener("syslog");
+        let mut sink = Syslog::new(path.clone()).unwrap();
+        sink.emit(&event()).unwrap();
+        let line = received(&listener);
+        assert!(line.starts_with("<30>1 "), "{}", line);
+        assert!(line.contains(" prom-cnproc "), "{}", line);
+        assert!(line.contains(" exit [cnproc@32473 "), "{}", line);
+        assert!(line.contains(" kind=\"exit\""), "{}", line);
+        assert!(line.contains(" tree=\"/sshd/bash/top\""), "{}", line);
+        assert!(line.contains(" argv=\"top -b\""), "{}", line);
+        assert!(line.ends_with("] /sshd/bash/top /usr/bin/top pid=3 code=1"), "{}", line);
+    }
+
+    #[test]
+    fn journald_native() {
+        let (listener, path, _dir) = listener("journald");
+        let mut sink = Journald::new(path.clone()).unwrap();
+        sink.emit(&event()).unwrap();
+        let datagram = received(&listener);
+        let lines: Vec<&str> = datagram.lines().collect();
+        assert!(lines.contains(&"SYSLOG_IDENTIFIER=prom-cnproc"));
+        assert!(lines.contains(&"CNPROC_TREE=/sshd/bash/top"));
+        assert!(lines.contains(&"CNPROC_EXE=/usr/bin/top"));
+        assert!(lines.contains(&"CNPROC_EXIT_CODE=1"));
+    }
+
+    #[test]
+    fn journald_multiline() {
+        let mut event = event();
+        event.argv = vec![String::from("sh"), String::from("-c"), String::from("a\nb")];
+        let sink = Journald::new(PathBuf::from("/nonexistent")).unwrap();
+        let datagram = sink.format(&event).unwrap();
+        let mut expected = b"CNPROC_ARGV\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"sh -c a\nb\n");
+        assert!(datagram.windows(expected.len()).any(|w| w == &expected[..]));
+    }
+}
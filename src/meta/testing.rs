@@ -0,0 +1,35 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Temporary folder for test fixtures, that is removed with everything in it,
+/// even when an assertion fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates empty `prom-cnproc-<name>-<pid>` folder, so names have to be unique across tests
+    pub fn new(name: &str) -> Self {
+        Self::under(&std::env::temp_dir(), name)
+    }
+
+    /// Creates the same folder in the given base folder instead of the temporary one
+    pub fn under(base: &Path, name: &str) -> Self {
+        let path = base.join(format!("prom-cnproc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use serde::Serialize;
+use super::info;
+
+/// Resources, that the process has used so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Usage {
+    /// CPU seconds in user mode
+    pub user: f64,
+    /// CPU seconds in kernel mode
+    pub system: f64,
+    /// Peak resident set size in bytes, that is gone once the process is a zombie
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rss_peak: Option<u64>,
+    /// Bytes, that were read from or written to storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_bytes: Option<u64>,
+}
+
+impl Usage {
+    /// Reads `/proc/<pid>/stat`, `status` and `io`, that are still there
+    /// for zombie processes, except for the memory
+    pub fn read(pid: i32) -> Option<Self> {
+        let ticks = info::stat_fields(pid, &[13, 14])?;
+        let per_second = info::clock_ticks() as f64;
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+        let io = fs::read_to_string(format!("/proc/{}/io", pid)).unwrap_or_default();
+        Some(Self{
+            user: ticks[0] as f64 / per_second,
+            system: ticks[1] as f64 / per_second,
+            rss_peak: field(&status, "VmHWM:").map(|kb| kb * 1024),
+            read_bytes: field(&io, "read_bytes:"),
+            write_bytes: field(&io, "write_bytes:"),
+        })
+    }
+
+    /// Combines with the earlier snapshot, as every value only grows
+    pub fn max(self, earlier: Option<Usage>) -> Self {
+        let earlier = match earlier {
+            Some(earlier) => earlier,
+            None => return self,
+        };
+        // None is less than any value
+        Self{
+            user: self.user.max(earlier.user),
+            system: self.system.max(earlier.system),
+            rss_peak: self.rss_peak.max(earlier.rss_peak),
+            read_bytes: self.read_bytes.max(earlier.read_bytes),
+            write_bytes: self.write_bytes.max(earlier.write_bytes),
+        }
+    }
+}
+
+/// Parses numeric value of `VmHWM:     1234 kB` or `read_bytes: 1234` line
+fn field(text: &str, name: &str) -> Option<u64> {
+    let line = text.lines().find(|l| l.starts_with(name))?;
+    line[name.len()..].split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(Some(5120), field("Name:\tcron\nVmHWM:\t    5120 kB\n", "VmHWM:"));
+        assert_eq!(Some(42), field("rchar: 1\nread_bytes: 42\nwrite_bytes: 0\n", "read_bytes:"));
+        assert_eq!(None, field("Name:\tcron\n", "VmHWM:"));
+    }
+
+    #[test]
+    fn reads_and_combines() {
+        let usage = Usage::read(std::process::id() as i32).unwrap();
+        assert!(usage.rss_peak.unwrap() > 0);
+        // more CPU time, than the test process could have used by now
+        let zombie = Usage{user: 1000., system: 0., rss_peak: None, read_bytes: Some(10), write_bytes: None};
+        let combined = zombie.max(Some(usage));
+        assert_eq!(usage.rss_peak, combined.rss_peak);
+        assert_eq!(1000., combined.user);
+        assert_eq!(Some(10).max(usage.read_bytes), combined.read_bytes);
+    }
+}
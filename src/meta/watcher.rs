@@ -1,72 +1,330 @@
 use std::{collections::HashMap};
-use cnproc::{PidMonitor, PidEvent};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use log::*;
-use metrics_exporter_prometheus::PrometheusBuilder;
-use super::info::Process;
-use std::io::Result;
-use metrics::{gauge, histogram};
-use std::net::SocketAddr;
+use super::aggregator::Aggregator;
+use super::alert::{Alert, Ancestor, Webhook};
+use super::baseline::{self, Baseline, Verdict};
+use super::config::Config;
+use super::connector::{Connector, ProcEvent};
+use super::environ::Environ;
+use super::event::{Event, Kind};
+use super::hasher::Hasher;
+use super::info::{Backing, Process};
+use super::location::Location;
+use super::miner::Miner;
+use super::namespaces;
+use super::packages;
+use super::randomness::{self, RandomnessDetector};
+use super::rules::{self, Rule};
+use super::recorder;
+use super::redact::Redactor;
+use super::sink::{self, Sink};
+use super::state::State;
+use super::usage::Usage;
+use std::io::{ErrorKind, Result};
+use metrics::{counter, gauge, histogram, increment_counter};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 
 #[cfg(target_os = "linux")]
 pub struct Watcher {
     pids: HashMap<i32,Process>,
-    monitor: PidMonitor
+    connector: Connector,
+    config: Config,
+    hasher: Option<Hasher>,
+    detector: Box<dyn RandomnessDetector>,
+    sinks: Vec<Box<dyn Sink>>,
+    rules: Vec<&'static Rule>,
+    webhook: Option<Webhook>,
+    baseline: Option<Baseline>,
+    state: Option<State>,
+    aggregator: Option<Aggregator>,
+    miner: Option<Miner>,
+    redactor: Redactor,
+    /// When resource usage of running processes was taken last time
+    snapshot: Instant,
+    /// How often resource usage is taken, more often for miner detection
+    snapshot_interval: Duration,
+    /// Processes, which resource usage is not taken yet in the current snapshot
+    snapshot_pending: Vec<i32>,
+    /// Environment is read only for configured variables or `ld-preload` rule
+    read_environ: bool,
+}
+
+/// How long to wait for events before doing periodic work or noticing,
+/// that the process has to stop and save its state
+const TICK: Duration = Duration::from_secs(1);
+/// How often resource usage of running processes is taken at least,
+/// so that peak memory is known after they become zombies
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+/// How many processes have their resource usage read between two events,
+/// so that large snapshots don't hold back events
+const SNAPSHOT_BATCH: usize = 256;
+
+/// Set by SIGTERM or SIGINT, so that state is saved before exit
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+/// Blocks or unblocks termination signals in the current thread. Threads
+/// inherit the mask, so blocking them before spawning any thread makes
+/// sure that signals interrupt only the main loop.
+fn mask_signals(how: libc::c_int) {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::pthread_sigmask(how, &set, std::ptr::null_mut());
+    }
+}
+
+/// Installs handler without `SA_RESTART`, so that blocking receive
+/// returns as soon as the signal arrives
+fn handle_signals() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+    mask_signals(libc::SIG_UNBLOCK);
 }
 
 /// Compacts the name for presentation in monitoring
-fn tree(pids: &HashMap<i32,Process>, pid: i32) -> String {
+fn tree(pids: &HashMap<i32,Process>, pid: i32, config: &Config) -> String {
     let mut curr = pid;
-    let mut tree = vec![];
-    // tree entropy is minumum entropy of any paths of binaries executed in this process tree
-    let mut tree_entropy = std::f32::MAX;
+    let mut tree: Vec<String> = vec![];
 
     while curr != 0 {
         trace!("tree curr={} {}", curr, tree.join("<"));
         if let Some(prc) = pids.get(&curr) {
             curr = prc.ppid;
-            // possible optimization: cache label and entropy per pid
-            let label = prc.label();
-
-            let path_entropy = prc.entropy();
-            if path_entropy < tree_entropy {
-                tree_entropy = path_entropy;
+            let label = &prc.label;
+            // sudo is labeled as base, so binary name is checked as well
+            if config.skip.iter().any(|s| s == label || s == prc.filename()) {
+                continue;
             }
-            if tree.last() == Some(&label) {
+            if tree.last() == Some(label) {
                 continue;
             }
-            if label == "systemd" {
+            // bash/base/bash is rolled up into bash
+            let window = tree.len().saturating_sub(config.collapse + 1);
+            if let Some(i) = tree[window..].iter().position(|l| l == label) {
+                tree.truncate(window + i + 1);
                 continue;
             }
-            tree.push(label);
+            tree.push(label.clone());
         } else {
             curr = 0
         }
     }
-    if tree_entropy < 0.022 {
-        // random prefix means that folder with binary was in random location
-        tree.push("random");
+    if let Some(depth) = config.max_depth {
+        if tree.len() > depth {
+            // keep the closest parents of the process
+            tree.truncate(depth);
+            tree.push(String::from("..."));
+        }
     }
     tree.reverse();
-    
-    return format!("/{}", tree.join("/"));
+
+    format!("/{}", tree.join("/"))
+}
+
+/// Caches label and tree of the process, which parents are already known,
+/// so that neither descendants nor exit of the process have to compute them
+fn remember(pids: &mut HashMap<i32,Process>, mut prc: Process, detector: &dyn RandomnessDetector, config: &Config) {
+    let pid = prc.pid;
+    prc.label = prc.compute_label(detector);
+    let parent = pids.get(&prc.ppid)
+        .filter(|parent| parent.pid != pid)
+        .and_then(|parent| extend(&parent.tree, &prc, config));
+    pids.insert(pid, prc);
+    let tree = match parent {
+        Some(tree) => tree,
+        None => tree(pids, pid, config),
+    };
+    if let Some(prc) = pids.get_mut(&pid) {
+        prc.tree = tree;
+    }
+}
+
+/// Appends the label of the process to the cached tree of its parent with
+/// the same rules as `tree()`, or returns `None`, when the parent tree is not
+/// known yet or its root is already cut by the maximum depth
+fn extend(parent: &str, prc: &Process, config: &Config) -> Option<String> {
+    if parent.is_empty() || parent.starts_with("/...") {
+        return None;
+    }
+    let label = &prc.label;
+    if config.skip.iter().any(|s| s == label || s == prc.filename()) {
+        return Some(String::from(parent));
+    }
+    let mut tree: Vec<&str> = parent.split('/').filter(|l| !l.is_empty()).collect();
+    if tree.last() == Some(&label.as_str()) {
+        return Some(String::from(parent));
+    }
+    // bash/base/bash is rolled up into bash
+    let window = tree.len().saturating_sub(config.collapse + 1);
+    if let Some(i) = tree[window..].iter().position(|l| l == label) {
+        tree.truncate(window + i + 1);
+    } else {
+        tree.push(label);
+    }
+    if let Some(depth) = config.max_depth {
+        if tree.len() > depth {
+            // keep the closest parents of the process
+            tree.drain(..tree.len() - depth);
+            tree.insert(0, "...");
+        }
+    }
+    Some(format!("/{}", tree.join("/")))
+}
+
+/// Reads the new image of already known process, that calls exec again, like
+/// nsenter does. Only start, parent and tree are kept from the known entry,
+/// and namespaces are compared to the replaced image instead of the parent.
+fn reexec(before: &Process, pid: i32) -> Result<Process> {
+    let mut prc = Process::new(pid)?;
+    prc.namespace_change = namespaces::change(&prc.namespaces, before);
+    prc.start = before.start;
+    prc.started = before.started;
+    prc.ppid = before.ppid;
+    prc.tree = before.tree.clone();
+    Ok(prc)
+}
+
+/// Returns parents of the process, closest first
+pub fn ancestry<'a>(pids: &'a HashMap<i32,Process>, prc: &Process) -> Vec<&'a Process> {
+    let mut parents = vec![];
+    let mut curr = prc.ppid;
+    while let Some(parent) = pids.get(&curr) {
+        if parent.pid == parent.ppid || parents.len() >= 64 {
+            break;
+        }
+        curr = parent.ppid;
+        parents.push(parent);
+    }
+    parents
+}
+
+/// Adds one more label to metric labels, like process state
+fn with(labels: &[(&'static str, String)], key: &'static str, value: &str) -> Vec<(&'static str, String)> {
+    let mut labels = labels.to_vec();
+    labels.push((key, String::from(value)));
+    labels
 }
 
 impl Watcher {
-    pub fn new() -> Result<Self> { 
-        let addr = "127.0.0.1:9501";
-        let addr: SocketAddr = addr
-            .parse()
-            .expect("Unable to parse socket address");
-        let monitor = PidMonitor::new()?;
-        let builder = PrometheusBuilder::new()
-            .listen_address(addr);
-        builder.install().expect("failed to install Prometheus recorder.");
-        Ok(Self{monitor, pids: HashMap::new()})
+    pub fn new(config: Config) -> Result<Self> {
+        mask_signals(libc::SIG_BLOCK);
+        let connector = Connector::new()?;
+        connector.set_timeout(TICK)?;
+        let (aggregator, totals) = recorder::install(&config)?;
+        let db = packages::db();
+        info!("loaded {} base binaries and {} packaged files from {}", db.len(), db.indexed(), db.source.as_str());
+        db.gauges();
+        packages::watch()?;
+        let hasher = match config.hash {
+            true => Some(Hasher::new()?),
+            false => None,
+        };
+        let detector = randomness::detector(&config.randomness, config.randomness_threshold);
+        let sinks = sink::from_config(&config)?;
+        let rules = rules::enabled(config.rules.as_deref(), &config.rules_disabled);
+        let webhook = match &config.webhook {
+            Some(url) => Some(Webhook::new(url.clone(), config.webhook_rate,
+                Duration::from_secs(config.webhook_dedup))?),
+            None => None,
+        };
+        let baseline = match &config.baseline {
+            Some(path) => {
+                let baseline = Baseline::open(PathBuf::from(path), Duration::from_secs(config.baseline_learn))?;
+                info!("{} trees of {}", if baseline.is_learning(baseline::now()) { "learning" } else { "enforcing" }, path);
+                Some(baseline)
+            }
+            None => None,
+        };
+        let mut pids = HashMap::new();
+        let state = match &config.state {
+            Some(path) => {
+                let (state, running, counters) = State::open(PathBuf::from(path),
+                    Duration::from_secs(config.state_interval));
+                for counter in counters {
+                    counter.restore(&totals);
+                }
+                for saved in running {
+                    match saved.restore() {
+                        Ok(prc) => {
+                            pids.insert(prc.pid, prc);
+                        }
+                        Err(e) => debug!("cannot restore pid {}: {}", saved.pid, e),
+                    }
+                }
+                info!("restored {} running processes", pids.len());
+                Some(state)
+            }
+            None => None,
+        };
+        let miner = match config.miner_interval {
+            0 => None,
+            _ if !rules.iter().any(|r| r.name == "miner") => None,
+            _ => Some(Miner::new(config.miner_threshold)),
+        };
+        let snapshot_interval = match miner {
+            Some(_) => SNAPSHOT_INTERVAL.min(Duration::from_secs(config.miner_interval)),
+            None => SNAPSHOT_INTERVAL,
+        };
+        let redactor = Redactor::new(config.redact.as_deref(), config.argv_max)?;
+        let read_environ = !config.environ.is_empty() || rules.iter().any(|r| r.name == "ld-preload");
+        Ok(Self{connector, pids, config, hasher, detector, sinks, rules, webhook, baseline, state, aggregator, miner,
+            redactor, snapshot: Instant::now(), snapshot_interval, snapshot_pending: vec![], read_environ})
+    }
+
+    /// Labels, that identify process in metrics
+    fn labels(&self, pid: i32, tree: &str) -> Vec<(&'static str, String)> {
+        let mut labels = vec![("tree", String::from(tree))];
+        if self.config.package_label {
+            let package = match self.pids.get(&pid) {
+                Some(prc) => prc.package(),
+                None => "unknown",
+            };
+            labels.push(("package", String::from(package)));
+        }
+        labels
+    }
+
+    /// Sends event to every configured sink
+    fn emit(&mut self, mut event: Event) {
+        event.argv = self.redactor.redact(&event.argv);
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.emit(&event) {
+                warn!("{} sink failed: {}", sink.name(), e);
+                increment_counter!("process_sink_errors_total", "sink" => sink.name());
+            }
+        }
     }
 
     fn start(&mut self, pid: i32) {
+        let replaced = match self.pids.get(&pid).map(|before| reexec(before, pid)) {
+            Some(Ok(mut prc)) => {
+                prc.label = prc.compute_label(self.detector.as_ref());
+                self.pids.insert(pid, prc);
+                true
+            }
+            Some(Err(e)) => {
+                warn!("pid {} > {}", pid, e);
+                true
+            }
+            None => false,
+        };
         let mut curr = pid;
+        let mut discovered = vec![];
         while curr != 0 {
             trace!("pid {} > curr {}", pid, curr);
             if self.pids.contains_key(&curr) {
@@ -82,39 +340,289 @@ impl Watcher {
                 }
             };
             curr = prc.ppid;
-            self.pids.insert(prc.pid, prc);
+            discovered.push(prc);
+        }
+        // parents go first, so that children reuse their labels
+        for prc in discovered.into_iter().rev() {
+            remember(&mut self.pids, prc, self.detector.as_ref(), &self.config);
+        }
+        let tree = match self.pids.get(&pid) {
+            Some(prc) => prc.tree.clone(),
+            None => tree(&self.pids, pid, &self.config),
+        };
+        if !replaced {
+            let change = self.pids.get(&pid)
+                .and_then(|prc| namespaces::change(&prc.namespaces, self.pids.get(&prc.ppid)?));
+            if let Some(prc) = self.pids.get_mut(&pid) {
+                prc.namespace_change = change;
+            }
+        }
+        if let Some(prc) = self.pids.get_mut(&pid) {
+            if self.read_environ {
+                let mut environ = Environ::read(pid, &self.config.environ);
+                for (key, value) in environ.vars.iter_mut() {
+                    *value = self.redactor.redact_var(key, value);
+                }
+                prc.environ = environ;
+            }
+        }
+        if let Some(prc) = self.pids.get(&pid) {
+            if rules::is_download(prc) {
+                let ppid = prc.ppid;
+                if let Some(parent) = self.pids.get_mut(&ppid) {
+                    parent.downloaded = Some(Instant::now());
+                }
+            }
+        }
+        let labels = self.labels(pid, &tree);
+        gauge!("process", 1.0, &with(&labels, "state", "RUNNING"));
+        gauge!("process", 0., &with(&labels, "state", "STOPPED"));
+        if let (Some(hasher), Some(prc)) = (&self.hasher, self.pids.get_mut(&pid)) {
+            prc.hashes = hasher.hash(prc);
+        }
+        if let Some(prc) = self.pids.get(&pid) {
+            if prc.backing != Backing::File {
+                let kind = prc.backing.as_str();
+                increment_counter!("process_fileless_exec_total", "tree" => tree.clone(), "kind" => kind);
+                info!("fileless exec pid={} kind={} tree={}", pid, kind, tree);
+            }
+            if prc.location != Location::Other {
+                let location = prc.location.as_str();
+                increment_counter!("process_location_exec_total", "tree" => tree.clone(), "location" => location);
+                debug!("exec from {} pid={} tree={}", location, pid, tree);
+            }
+            if let Some(kind) = prc.namespace_change {
+                increment_counter!("process_namespace_change_total", "tree" => tree.clone(), "kind" => kind);
+                debug!("namespace change pid={} kind={} tree={}", pid, kind, tree);
+            }
+            if !prc.environ.preload.is_empty() {
+                increment_counter!("process_preload_exec_total", "tree" => tree.clone());
+                debug!("preload pid={} paths={:?} tree={}", pid, prc.environ.preload, tree);
+            }
+            for kind in prc.privileges.kinds() {
+                increment_counter!("process_privileged_exec_total", "tree" => tree.clone(), "kind" => kind);
+                debug!("privileged exec pid={} kind={} tree={}", pid, kind, tree);
+            }
+            let matched: Vec<&'static Rule> = {
+                let parents = ancestry(&self.pids, prc);
+                self.rules.iter().copied().filter(|r| r.matches(prc, &parents)).collect()
+            };
+            if let Some(state) = &mut self.state {
+                state.exec(&tree, baseline::now());
+            }
+            let verdict = match &mut self.baseline {
+                Some(baseline) => baseline.observe(&tree, baseline::now()),
+                None => Verdict::Expected,
+            };
+            if verdict != Verdict::Expected {
+                increment_counter!("process_unexpected_total", "tree" => tree.clone());
+            }
+            let first_seen = verdict == Verdict::FirstSeen;
+            if !self.sinks.is_empty() || !matched.is_empty() || first_seen {
+                let event = Event::new(Kind::Exec, prc);
+                for rule in matched {
+                    info!("rule {} matched pid={} tree={}", rule.name, pid, tree);
+                    increment_counter!("process_rule_matches_total", "rule" => rule.name);
+                    self.alert(pid, rule.name, rule.description, &event);
+                    let mut alert = event.clone();
+                    alert.kind = Kind::Alert;
+                    alert.rule = Some(rule.name);
+                    self.emit(alert);
+                }
+                if first_seen {
+                    warn!("unexpected tree pid={} tree={}", pid, tree);
+                    let mut unexpected = event.clone();
+                    unexpected.kind = Kind::Unexpected;
+                    self.alert(pid, "unexpected-tree", "tree is not in the baseline", &unexpected);
+                    self.emit(unexpected);
+                }
+                self.emit(event);
+            }
         }
-        let tree = tree(&self.pids, pid);
-        gauge!("process", 1.0, "tree" => tree.clone(), "state" => "RUNNING");
-        gauge!("process", 0., "tree" => tree.clone(), "state" => "STOPPED");
         debug!("started pid={} tree={}", pid, tree)
     }
 
-    fn stop(&mut self, pid: i32) {
+    /// Sends alert with ancestry of the process to webhook
+    fn alert(&mut self, pid: i32, rule: &'static str, description: &'static str, event: &Event) {
+        let webhook = match &mut self.webhook {
+            Some(webhook) => webhook,
+            None => return,
+        };
+        let redactor = &self.redactor;
+        let ancestry = match self.pids.get(&pid) {
+            Some(prc) => ancestry(&self.pids, prc).into_iter()
+                .map(|p| Ancestor{argv: redactor.redact(&p.argv), ..Ancestor::from(p)})
+                .collect(),
+            None => vec![],
+        };
+        let mut event = event.clone();
+        event.argv = redactor.redact(&event.argv);
+        webhook.notify(Alert{rule, description, event, ancestry});
+    }
+
+    /// Forked process is the copy of the parent until it calls exec
+    fn fork(&mut self, parent: i32, pid: i32) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        if let Some(prc) = self.pids.get(&parent) {
+            let mut event = Event::new(Kind::Fork, prc);
+            event.pid = pid;
+            event.ppid = parent;
+            self.emit(event);
+        }
+    }
+
+    fn change_uid(&mut self, pid: i32, euid: u32) {
+        let event = match self.pids.get_mut(&pid) {
+            Some(prc) => {
+                prc.uid = euid;
+                if let Some(hasher) = &self.hasher {
+                    hasher.fill(&mut prc.hashes);
+                }
+                Event::new(Kind::Uid, prc)
+            }
+            None => return,
+        };
+        debug!("changed uid pid={} uid={} tree={}", pid, euid, event.tree);
+        self.emit(event);
+    }
+
+    fn stop(&mut self, pid: i32, status: u32) {
         if !self.pids.contains_key(&pid) {
             // don't trigger for before unknown processes
             return;
         }
-        let tree = tree(&self.pids, pid);
-        let prc = self.pids.remove(&pid).unwrap();
+        let tree = self.pids[&pid].tree.clone();
+        let labels = self.labels(pid, &tree);
+        let mut prc = self.pids.remove(&pid).unwrap();
+        if let Some(hasher) = &self.hasher {
+            // hash of the new binary is usually ready by now, unlike at exec
+            hasher.fill(&mut prc.hashes);
+        }
         let elapsed = prc.start.elapsed();
         let seconds = elapsed.as_secs_f64();
+        // exit is reported before the parent reaps the zombie, but after the memory is released.
+        // Exit event of the connector has only the code and signal, so the rest is counted as missing.
+        let usage = Usage::read(pid).map(|u| u.max(prc.usage)).or(prc.usage);
 
-        gauge!("process", 0., "tree" => tree.clone(), "state" => "RUNNING");
-        gauge!("process", 1., "tree" => tree.clone(), "state" => "STOPPED");
-        histogram!("process_seconds", seconds, "tree" => tree.clone());
+        gauge!("process", 0., &with(&labels, "state", "RUNNING"));
+        gauge!("process", 1., &with(&labels, "state", "STOPPED"));
+        histogram!("process_seconds", seconds, &labels);
+        match &usage {
+            Some(usage) => {
+                histogram!("process_cpu_seconds", usage.user, &with(&labels, "mode", "user"));
+                histogram!("process_cpu_seconds", usage.system, &with(&labels, "mode", "system"));
+                match usage.rss_peak {
+                    Some(rss) => histogram!("process_rss_peak_bytes", rss as f64, &labels),
+                    None => increment_counter!("process_usage_missing_total", "field" => "rss_peak"),
+                }
+                match (usage.read_bytes, usage.write_bytes) {
+                    (Some(read), Some(write)) => {
+                        counter!("process_read_bytes_total", read, &labels);
+                        counter!("process_write_bytes_total", write, &labels);
+                    }
+                    _ => increment_counter!("process_usage_missing_total", "field" => "io"),
+                }
+            }
+            None => increment_counter!("process_usage_missing_total", "field" => "all"),
+        }
+        if let Some(state) = &mut self.state {
+            state.exit(&tree, baseline::now());
+        }
         debug!("stopped pid={} tree={} duration={:?}", pid, tree, elapsed);
+        if !self.sinks.is_empty() {
+            let mut event = Event::new(Kind::Exit, &prc);
+            event.duration = Some(seconds);
+            event.usage = usage;
+            // same encoding as the status from wait(2)
+            if status & 0x7f == 0 {
+                event.exit_code = Some(status >> 8 & 0xff);
+            } else {
+                event.exit_signal = Some(status & 0x7f);
+            }
+            self.emit(event);
+        }
+    }
+
+    /// Reports processes, that look like crypto-miners
+    /// Reads resource usage of the next batch of processes in the current
+    /// snapshot and samples miners, once the whole snapshot is taken
+    fn snapshot_batch(&mut self) {
+        let rest = self.snapshot_pending.len().saturating_sub(SNAPSHOT_BATCH);
+        for pid in self.snapshot_pending.drain(rest..) {
+            if let Some(prc) = self.pids.get_mut(&pid) {
+                prc.usage = Usage::read(pid).or(prc.usage);
+            }
+        }
+        if self.snapshot_pending.is_empty() {
+            self.sample();
+        }
+    }
+
+    fn sample(&mut self) {
+        let crossed = match &mut self.miner {
+            Some(miner) => miner.sample(&self.pids),
+            None => return,
+        };
+        for (pid, score) in crossed {
+            let mut event = match self.pids.get(&pid) {
+                Some(prc) => Event::new(Kind::Alert, prc),
+                None => continue,
+            };
+            warn!("miner suspected pid={} score={:.2} tree={}", pid, score, event.tree);
+            increment_counter!("process_rule_matches_total", "rule" => "miner");
+            event.rule = Some("miner");
+            event.score = Some(score);
+            self.alert(pid, "miner", "process runs long and hot and looks like crypto-miner", &event);
+            self.emit(event);
+        }
+    }
+
+    /// Writes state and baseline, so that nothing is lost on restart
+    fn save(&mut self) {
+        if let Some(state) = &mut self.state {
+            if let Err(e) = state.save(&self.pids, self.aggregator.as_ref()) {
+                warn!("cannot save state: {}", e);
+            }
+        }
+        if let Some(baseline) = &mut self.baseline {
+            if let Err(e) = baseline.save() {
+                warn!("cannot save baseline: {}", e);
+            }
+        }
     }
 
     pub fn main_loop(&mut self) -> ! {
+        handle_signals();
         loop {
-            if let Some(e) = self.monitor.recv() {
-                match e {
-                    PidEvent::Exec(pid) => self.start(pid),
-                    PidEvent::Exit(pid) => self.stop(pid),
-                    _ => continue
+            if STOPPING.load(Ordering::SeqCst) {
+                info!("stopping...");
+                self.save();
+                process::exit(0);
+            }
+            if let Some(state) = self.state.as_mut().filter(|s| s.is_due()) {
+                if let Err(e) = state.save(&self.pids, self.aggregator.as_ref()) {
+                    warn!("cannot save state: {}", e);
                 }
             }
+            if self.snapshot.elapsed() >= self.snapshot_interval && self.snapshot_pending.is_empty() {
+                self.snapshot = Instant::now();
+                self.snapshot_pending = self.pids.keys().copied().collect();
+            }
+            if !self.snapshot_pending.is_empty() {
+                self.snapshot_batch();
+            }
+            match self.connector.recv() {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // no events during the tick
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Ok(ProcEvent::Exec{pid}) => self.start(pid),
+                Ok(ProcEvent::Exit{pid, code, ..}) => self.stop(pid, code),
+                Ok(ProcEvent::Fork{parent, pid}) => self.fork(parent, pid),
+                Ok(ProcEvent::Uid{pid, euid, ..}) => self.change_uid(pid, euid),
+                Err(e) => warn!("cannot receive process events: {}", e),
+            }
         }
     }
 }
@@ -122,23 +630,147 @@ impl Watcher {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::randomness::Entropy;
     use std::vec;
 
     #[test]
     fn cmdline_parses() {
         let mut pids = HashMap::new();
+        let detector = Entropy::new(3.4);
+        let config = Config::default();
 
-        pids.insert(1, Process::from(1, 0, "/usr/bin/bash", vec![]));
-        pids.insert(2, Process::from(2, 1, "/usr/sbin/sshd", vec![]));
-        pids.insert(3, Process::from(3, 2, "/bin/bash", vec![
+        remember(&mut pids, Process::from(1, 0, "/usr/bin/bash", vec![]), &detector, &config);
+        remember(&mut pids, Process::from(2, 1, "/usr/sbin/sshd", vec![]), &detector, &config);
+        remember(&mut pids, Process::from(3, 2, "/bin/bash", vec![
             String::from("sh"),
             String::from("/etc/init.d/hwclock.sh"),
             String::from("-a"),
             String::from("-b"),
-        ]));
-        let t = tree(&pids, 3);
+        ]), &detector, &config);
+        let t = tree(&pids, 3, &config);
 
         // unknown is the default username for pid "2", that is not likely to exist
-        assert_eq!("/base/sshd/hwclock.sh", t)
+        assert_eq!("/base/sshd/hwclock.sh", t);
+        assert_eq!(t, pids[&3].tree);
+    }
+
+    type TreeCase<'a> = (&'a str, Vec<&'a str>, Vec<(&'a str, &'a str)>, &'a str);
+
+    /// Builds the chain of processes, where the first one is the root
+    fn chain(exes: &[&str], config: &Config) -> HashMap<i32,Process> {
+        let mut pids = HashMap::new();
+        for (i, exe) in exes.iter().enumerate() {
+            let pid = i as i32 + 1;
+            let prc = Process::from(pid, i as i32, exe, vec![]);
+            remember(&mut pids, prc, &Entropy::new(3.4), config);
+        }
+        pids
+    }
+
+    #[test]
+    fn tree_rules() {
+        // name, executables from the root, configuration, expected tree
+        let cases: Vec<TreeCase> = vec![
+            ("adjacent duplicates",
+                vec!["/opt/bin/node", "/opt/bin/node", "/opt/bin/npm"], vec![],
+                "/node/npm"),
+            ("systemd is skipped by default",
+                vec!["/opt/bin/systemd", "/opt/bin/cron", "/opt/bin/job"], vec![],
+                "/cron/job"),
+            ("configured skip list",
+                vec!["/opt/bin/containerd-shim", "/opt/bin/tini", "/opt/bin/app"],
+                vec![("CNPROC_SKIP", "containerd-shim,tini")],
+                "/app"),
+            ("non-adjacent repeats are kept by default",
+                vec!["/opt/bin/sshd", "/opt/bin/bash", "/opt/bin/sudo", "/opt/bin/bash", "/opt/bin/top"], vec![],
+                "/sshd/bash/sudo/bash/top"),
+            ("non-adjacent repeats",
+                vec!["/opt/bin/sshd", "/opt/bin/bash", "/opt/bin/sudo", "/opt/bin/bash", "/opt/bin/top"],
+                vec![("CNPROC_COLLAPSE", "1")],
+                "/sshd/bash/top"),
+            ("repeats further than collapse window",
+                vec!["/opt/bin/bash", "/opt/bin/sudo", "/opt/bin/su", "/opt/bin/bash"],
+                vec![("CNPROC_COLLAPSE", "1")],
+                "/bash/sudo/su/bash"),
+            ("wider collapse window",
+                vec!["/opt/bin/bash", "/opt/bin/sudo", "/opt/bin/su", "/opt/bin/bash"],
+                vec![("CNPROC_COLLAPSE", "2")],
+                "/bash"),
+            ("max depth",
+                vec!["/opt/bin/alpha", "/opt/bin/bravo", "/opt/bin/charlie", "/opt/bin/delta"],
+                vec![("CNPROC_MAX_DEPTH", "2")],
+                "/.../charlie/delta"),
+            ("max depth is not reached",
+                vec!["/opt/bin/alpha", "/opt/bin/bravo"],
+                vec![("CNPROC_MAX_DEPTH", "2")],
+                "/alpha/bravo"),
+        ];
+        for (name, exes, vars, expected) in cases {
+            let config = Config::from_vars(vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())));
+            let pids = chain(&exes, &config);
+            let pid = exes.len() as i32;
+            assert_eq!(expected, pids[&pid].tree, "{}", name);
+            // parent tree is extended the same way, as the whole tree is walked
+            assert_eq!(tree(&pids, pid, &config), pids[&pid].tree, "{}", name);
+        }
+    }
+
+    #[test]
+    fn reexec_reads_new_image() {
+        use std::io::Write;
+        let mut child = std::process::Command::new("sh").args(["-c", "read x; exec sleep 10"])
+            .stdin(std::process::Stdio::piped())
+            .spawn().unwrap();
+        let pid = child.id() as i32;
+        let mut before = Process::new(pid).unwrap();
+        before.tree = String::from("/bash/sh");
+        child.stdin.take().unwrap().write_all(b"go\n").unwrap();
+        let exe = format!("/proc/{}/exe", pid);
+        for _ in 0..100 {
+            if std::fs::read_link(&exe).map(|p| p.ends_with("sleep")).unwrap_or(false) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let prc = reexec(&before, pid).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let event = Event::new(Kind::Exec, &prc);
+        assert!(event.exe.ends_with("/sleep"), "{}", event.exe);
+        assert_ne!(before.exe(), prc.exe());
+        assert_eq!(["sleep", "10"], prc.argv[..2]);
+        assert_eq!(before.start, prc.start);
+        assert_eq!("/bash/sh", prc.tree);
+        assert_eq!(None, prc.namespace_change);
+    }
+
+    /// Measures caching of labels and trees in `remember()`, when every exec
+    /// is a child of a deep tree, like in a busy CI runner. It doesn't cover
+    /// reading `/proc` in `Watcher::start`, which depends on the host:
+    /// `cargo test --release -- --ignored remember_deep_tree`
+    #[test]
+    #[ignore]
+    fn remember_deep_tree() {
+        let config = Config::default();
+        let detector = Entropy::new(3.4);
+        let mut pids = HashMap::new();
+        let depth = 32;
+        for pid in 1..=depth {
+            let exe = format!("/opt/runner/bin/step-{}", pid % 5);
+            remember(&mut pids, Process::from(pid, pid - 1, &exe, vec![]), &detector, &config);
+        }
+        let execs = 100_000;
+        let started = std::time::Instant::now();
+        for i in 0..execs {
+            let pid = depth + 1 + i;
+            let exe = format!("/tmp/target/debug/deps/job-{:016x}", i);
+            remember(&mut pids, Process::from(pid, depth, &exe, vec![]), &detector, &config);
+            // exit is reading the cached tree
+            let prc = pids.remove(&pid).unwrap();
+            assert!(prc.tree.ends_with("/job-{hash}"));
+        }
+        let per_exec = started.elapsed() / execs as u32;
+        println!("remember() with depth {} took {:?}", depth, per_exec);
     }
-}
\ No newline at end of file
+}